@@ -95,6 +95,7 @@ if (-not (Test-Path ".dev.vars")) {
     Copy-Item ".dev.vars.example" ".dev.vars"
     Write-Warning "IMPORTANTE: Edita .dev.vars con tu clave real"
     Write-Warning "- TURNSTILE_SECRET_KEY: De Cloudflare Dashboard > Turnstile"
+    Write-Warning "- TOKEN_LOOKUP_SECRET: Genera con openssl rand -base64 32"
     Write-Warning "- JWT secrets ahora se generan automáticamente por usuario (512-bit)"
 } else {
     Write-Warning ".dev.vars ya existe, no se sobrescribirá"
@@ -107,7 +108,7 @@ Write-Warning "Pasos siguientes:"
 Write-Host "1. 🔑 Configura Turnstile en Cloudflare Dashboard" -ForegroundColor White
 Write-Host "2. 📝 Edita .dev.vars con tu clave TURNSTILE_SECRET_KEY" -ForegroundColor White
 Write-Host "3. 🚀 Ejecuta: npm run dev" -ForegroundColor White
-Write-Host "4. 🌐 Para producción: wrangler secret put TURNSTILE_SECRET_KEY" -ForegroundColor White
+Write-Host "4. 🌐 Para producción: wrangler secret put TURNSTILE_SECRET_KEY && wrangler secret put TOKEN_LOOKUP_SECRET" -ForegroundColor White
 Write-Host ""
 Write-Success "🔐 MEJORA DE SEGURIDAD: JWT secrets únicos por usuario"
 Write-Host "- Cada usuario tiene su propia clave JWT de 512-bit" -ForegroundColor Green
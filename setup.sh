@@ -70,6 +70,7 @@ if [ ! -f .dev.vars ]; then
     cp .dev.vars.example .dev.vars
     print_warning "IMPORTANTE: Edita .dev.vars con tu clave real"
     print_warning "- TURNSTILE_SECRET_KEY: De Cloudflare Dashboard > Turnstile"
+    print_warning "- TOKEN_LOOKUP_SECRET: Genera con openssl rand -base64 32"
     print_warning "- JWT secrets ahora se generan automáticamente por usuario (512-bit)"
 else
     print_warning ".dev.vars ya existe, no se sobrescribirá"
@@ -82,7 +83,7 @@ print_warning "Pasos siguientes:"
 echo "1. 🔑 Configura Turnstile en Cloudflare Dashboard"
 echo "2. 📝 Edita .dev.vars con tu clave TURNSTILE_SECRET_KEY"
 echo "3. 🚀 Ejecuta: npm run dev"
-echo "4. 🌐 Para producción: wrangler secret put TURNSTILE_SECRET_KEY"
+echo "4. 🌐 Para producción: wrangler secret put TURNSTILE_SECRET_KEY && wrangler secret put TOKEN_LOOKUP_SECRET"
 echo ""
 print_success "🔐 MEJORA DE SEGURIDAD: JWT secrets únicos por usuario"
 echo "- Cada usuario tiene su propia clave JWT de 512-bit"
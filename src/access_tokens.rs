@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use crate::token_lookup::{lookup_tag, verify_lookup_tag, to_hex, ACCESS_TOKEN_LOOKUP_CONTEXT};
+use crate::kv_index::TimeIndex;
+
+// Personal access tokens look like "pat_<16 hex id>_<32 hex lookup tag>_<secret>" so they are
+// recognisable in logs and scanners, and forged ids are rejected before the owner lookup
+pub const ACCESS_TOKEN_PREFIX: &str = "pat_";
+
+// KV key prefix mapping a token id to the username that owns it
+const ACCESS_TOKEN_OWNER_PREFIX: &str = "pat_owner:";
+
+// Time index of expiring tokens, "pat_expiry:<expires_at>:<id>", listed by the prune job.
+// Entries of tokens that are already gone are dropped when they come due.
+const EXPIRY_INDEX: TimeIndex = TimeIndex::new("pat_expiry:");
+
+// Tokens are stored inside UserData, keep the record bounded
+pub const MAX_ACCESS_TOKENS_PER_USER: usize = 25;
+const MAX_ACCESS_TOKEN_NAME_BYTES: usize = 100;
+const MAX_ACCESS_TOKEN_LIFETIME_DAYS: i64 = 3650;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PersonalAccessToken {
+    pub id: String,
+    pub name: String,
+    pub token_hash: String, // Hex encoded SHA-256 of the full token, the token itself is never stored
+    pub scope: String,
+    pub created_at: i64,
+    pub expires_at: Option<i64>, // None means the token does not expire
+}
+
+// What clients get to see about a token, never includes the hash
+#[derive(Serialize)]
+pub struct AccessTokenSummary {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scope: String,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+impl From<&PersonalAccessToken> for AccessTokenSummary {
+    fn from(token: &PersonalAccessToken) -> Self {
+        AccessTokenSummary {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            prefix: format!("{}{}", ACCESS_TOKEN_PREFIX, token.id),
+            scope: token.scope.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateAccessTokenRequest {
+    pub name: String,
+    pub scope: Option<String>,        // Defaults to DEFAULT_TOKEN_SCOPES
+    pub expires_in_days: Option<i64>, // Omit for a token that never expires
+    pub current_password: Option<String>, // Step-up, unless the JWT is from a recent login
+}
+
+impl CreateAccessTokenRequest {
+    // Expiry time of the new token, the error says which field is out of range
+    pub fn validate(&self, now: i64) -> std::result::Result<Option<i64>, String> {
+        if self.name.trim().is_empty() || self.name.len() > MAX_ACCESS_TOKEN_NAME_BYTES {
+            return Err(format!("name must be 1 to {} bytes", MAX_ACCESS_TOKEN_NAME_BYTES));
+        }
+
+        match self.expires_in_days {
+            None => Ok(None),
+            Some(days) if (1..=MAX_ACCESS_TOKEN_LIFETIME_DAYS).contains(&days) => Ok(Some(now + days * 24 * 60 * 60)),
+            Some(_) => Err(format!("expires_in_days must be between 1 and {}", MAX_ACCESS_TOKEN_LIFETIME_DAYS)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreateAccessTokenResponse {
+    pub success: bool,
+    pub message: String,
+    pub token: String, // Only returned once, at creation time
+    pub access_token: AccessTokenSummary,
+}
+
+#[derive(Serialize)]
+pub struct ListAccessTokensResponse {
+    pub success: bool,
+    pub access_tokens: Vec<AccessTokenSummary>,
+}
+
+// Generate a new token, returning its id and the full secret token string
+pub fn generate_access_token(lookup_secret: &[u8]) -> (String, String) {
+    let mut id = [0u8; 8];
+    OsRng.fill_bytes(&mut id);
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+
+    let id = to_hex(&id);
+    let tag = lookup_tag(lookup_secret, ACCESS_TOKEN_LOOKUP_CONTEXT, &id);
+    let token = format!("{}{}_{}_{}", ACCESS_TOKEN_PREFIX, id, tag, general_purpose::URL_SAFE_NO_PAD.encode(secret));
+    (id, token)
+}
+
+// Tokens carry 256 bits of entropy, so a fast hash is sufficient (unlike passwords)
+pub fn hash_access_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+// Compare a presented token against a stored record without leaking timing information
+pub fn verify_access_token(token: &str, record: &PersonalAccessToken) -> bool {
+    hash_access_token(token)
+        .as_bytes()
+        .ct_eq(record.token_hash.as_bytes())
+        .into()
+}
+
+pub fn is_access_token(token: &str) -> bool {
+    token.starts_with(ACCESS_TOKEN_PREFIX)
+}
+
+// Extract the token id from "pat_<id>_<tag>_<secret>", only if its lookup tag is authentic
+pub fn parse_access_token_id<'a>(token: &'a str, lookup_secret: &[u8]) -> Option<&'a str> {
+    let mut parts = token.strip_prefix(ACCESS_TOKEN_PREFIX)?.splitn(3, '_');
+    let (id, tag, secret) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let well_formed = id.len() == 16 && id.chars().all(|c| c.is_ascii_hexdigit()) && !secret.is_empty();
+    if well_formed && verify_lookup_tag(lookup_secret, ACCESS_TOKEN_LOOKUP_CONTEXT, id, tag) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+pub async fn store_access_token_owner(env: &Env, token_id: &str, username: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    kv.put(&format!("{}{}", ACCESS_TOKEN_OWNER_PREFIX, token_id), username)?.execute().await?;
+    Ok(())
+}
+
+pub async fn get_access_token_owner(env: &Env, token_id: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    find_access_token_owner(env, token_id).await?.ok_or_else(|| "Access token not found".into())
+}
+
+// None when the token has no owner entry, Err only when the read failed
+pub async fn find_access_token_owner(env: &Env, token_id: &str) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    Ok(kv.get(&format!("{}{}", ACCESS_TOKEN_OWNER_PREFIX, token_id)).text().await?)
+}
+
+pub async fn delete_access_token_owner(env: &Env, token_id: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    kv.delete(&format!("{}{}", ACCESS_TOKEN_OWNER_PREFIX, token_id)).await?;
+    Ok(())
+}
+
+// Owner and expiry index entries of a token already removed from its user record
+pub async fn delete_access_token_entries(env: &Env, token: &PersonalAccessToken) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    delete_access_token_owner(env, &token.id).await?;
+    if let Some(expires_at) = token.expires_at {
+        remove_from_expiry_index(env, &token.id, expires_at).await?;
+    }
+    Ok(())
+}
+
+pub async fn add_to_expiry_index(env: &Env, token: &PersonalAccessToken) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    if let Some(expires_at) = token.expires_at {
+        EXPIRY_INDEX.add(env, expires_at, &token.id).await?;
+    }
+    Ok(())
+}
+
+pub async fn remove_from_expiry_index(env: &Env, token_id: &str, expires_at: i64) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    EXPIRY_INDEX.remove(env, expires_at, token_id).await
+}
+
+// Up to `limit` (token id, expires_at) entries of tokens expired before `now`, oldest first. A
+// token is still valid during its expires_at second.
+pub async fn expired_access_tokens(env: &Env, now: i64, limit: u64) -> std::result::Result<Vec<(String, i64)>, Box<dyn std::error::Error>> {
+    EXPIRY_INDEX.due(env, now - 1, limit).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_request(name: &str, expires_in_days: Option<i64>) -> CreateAccessTokenRequest {
+        CreateAccessTokenRequest { name: name.to_string(), scope: None, expires_in_days, current_password: None }
+    }
+
+    const SECRET: &[u8] = b"token lookup secret";
+
+    #[test]
+    fn generated_token_id_parses_back() {
+        let (id, token) = generate_access_token(SECRET);
+
+        assert!(is_access_token(&token));
+        assert_eq!(parse_access_token_id(&token, SECRET), Some(id.as_str()));
+    }
+
+    #[test]
+    fn token_id_needs_an_authentic_tag() {
+        let (id, token) = generate_access_token(SECRET);
+        assert_eq!(parse_access_token_id(&token, b"other secret"), None);
+
+        let (other_id, _) = generate_access_token(SECRET);
+        let swapped = token.replacen(&id, &other_id, 1);
+        assert_eq!(parse_access_token_id(&swapped, SECRET), None);
+    }
+
+    #[test]
+    fn malformed_tokens_have_no_id() {
+        let (id, token) = generate_access_token(SECRET);
+        let tag = lookup_tag(SECRET, ACCESS_TOKEN_LOOKUP_CONTEXT, &id);
+
+        assert_eq!(parse_access_token_id(&format!("pat_{}_{}_", id, tag), SECRET), None);
+        assert_eq!(parse_access_token_id(&format!("pat_{}_{}", id, tag), SECRET), None);
+        assert_eq!(parse_access_token_id(token.trim_start_matches(ACCESS_TOKEN_PREFIX), SECRET), None);
+        assert_eq!(parse_access_token_id("pat_../../config_x_y", SECRET), None);
+    }
+
+    #[test]
+    fn lifetime_in_range_sets_the_expiry() {
+        assert_eq!(create_request("ci", None).validate(1000), Ok(None));
+        assert_eq!(create_request("ci", Some(1)).validate(1000), Ok(Some(1000 + 86400)));
+        assert!(create_request("ci", Some(MAX_ACCESS_TOKEN_LIFETIME_DAYS)).validate(1000).is_ok());
+    }
+
+    #[test]
+    fn lifetime_out_of_range_is_rejected() {
+        for days in [0, -1, MAX_ACCESS_TOKEN_LIFETIME_DAYS + 1, i64::MAX, i64::MIN] {
+            assert!(create_request("ci", Some(days)).validate(1000).is_err(), "{} days", days);
+        }
+    }
+
+    #[test]
+    fn name_must_be_present_and_bounded() {
+        assert!(create_request("", None).validate(0).is_err());
+        assert!(create_request("   ", None).validate(0).is_err());
+        assert!(create_request(&"x".repeat(MAX_ACCESS_TOKEN_NAME_BYTES), None).validate(0).is_ok());
+        assert!(create_request(&"é".repeat(MAX_ACCESS_TOKEN_NAME_BYTES / 2 + 1), None).validate(0).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use worker::*;
+use base64::{Engine as _, engine::general_purpose};
+use crate::auth::{UserData, PendingDeletion};
+use crate::kv_store::delete_user_from_kv;
+use crate::access_tokens::delete_access_token_entries;
+use crate::login_history::delete_login_history;
+use crate::audit::AuditSink;
+use crate::token_lookup::{lookup_tag, verify_lookup_tag};
+use crate::config::env_parse;
+use crate::kv_index::TimeIndex;
+
+// DELETE /user only marks the account for deletion. For DELETION_GRACE_PERIOD_DAYS it can be
+// restored with the restore token handed out on deletion or by an admin, then the
+// purge_deleted_accounts maintenance job deletes it for good. A grace period of 0 deletes at once.
+//
+// Restore tokens are "<base64url username>.<purge_at>.<tag>", the tag an HMAC under
+// TOKEN_LOOKUP_SECRET that also covers the JWT version. Deleting bumps that version, so a token
+// only restores the deletion it was issued for.
+//
+// Every pending deletion also has an entry "pending_deletion:<purge_at>:<username>" in a time
+// index, so the purge job lists only the due ones instead of every user.
+
+const RESTORE_TOKEN_CONTEXT: &str = "account-restore";
+const DEFAULT_GRACE_PERIOD_DAYS: i64 = 30;
+const PURGE_INDEX: TimeIndex = TimeIndex::new("pending_deletion:");
+
+pub fn grace_period_seconds(env: &Env) -> i64 {
+    env_parse(env, "DELETION_GRACE_PERIOD_DAYS", DEFAULT_GRACE_PERIOD_DAYS)
+        .max(0)
+        * 24
+        * 3600
+}
+
+pub fn schedule_deletion(user_data: &mut UserData, now: i64, grace_period_seconds: i64) -> PendingDeletion {
+    let pending = PendingDeletion {
+        requested_at: now,
+        purge_at: now + grace_period_seconds,
+    };
+    user_data.pending_deletion = Some(pending.clone());
+    pending
+}
+
+pub async fn add_to_purge_index(env: &Env, username: &str, purge_at: i64) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    PURGE_INDEX.add(env, purge_at, username).await
+}
+
+pub async fn remove_from_purge_index(env: &Env, username: &str, purge_at: i64) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    PURGE_INDEX.remove(env, purge_at, username).await
+}
+
+// Up to `limit` (username, purge_at) entries that are due at `now`, oldest first
+pub async fn due_deletions(env: &Env, now: i64, limit: u64) -> std::result::Result<Vec<(String, i64)>, Box<dyn std::error::Error>> {
+    PURGE_INDEX.due(env, now, limit).await
+}
+
+fn restore_tag_input(username: &str, purge_at: i64, jwt_version: u32) -> String {
+    format!("{}:{}:{}", username, purge_at, jwt_version)
+}
+
+pub fn generate_restore_token(secret: &[u8], user_data: &UserData, purge_at: i64) -> String {
+    let tag = lookup_tag(secret, RESTORE_TOKEN_CONTEXT, &restore_tag_input(&user_data.username, purge_at, user_data.jwt_version));
+    format!("{}.{}.{}", general_purpose::URL_SAFE_NO_PAD.encode(&user_data.username), purge_at, tag)
+}
+
+// The username a restore token names. Only trust it once verify_restore_token accepted the token.
+pub fn restore_token_username(token: &str) -> Option<String> {
+    let encoded = token.split('.').next()?;
+    let username = general_purpose::URL_SAFE_NO_PAD.decode(encoded).ok()?;
+    String::from_utf8(username).ok()
+}
+
+// Valid while the deletion it was issued for is still pending and its grace period has not ended
+pub fn verify_restore_token(secret: &[u8], user_data: &UserData, token: &str, now: i64) -> bool {
+    let Some(pending) = &user_data.pending_deletion else {
+        return false;
+    };
+    let mut parts = token.split('.');
+    let (Some(_), Some(purge_at), Some(tag), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+
+    purge_at == pending.purge_at.to_string()
+        && now < pending.purge_at
+        && verify_lookup_tag(secret, RESTORE_TOKEN_CONTEXT, &restore_tag_input(&user_data.username, pending.purge_at, user_data.jwt_version), tag)
+}
+
+// Hard delete: the user record, the lookup entries of its access tokens and its login history
+pub async fn purge_user(env: &Env, user_data: &UserData) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    delete_user_from_kv(env, &user_data.username).await?;
+    for access_token in &user_data.access_tokens {
+        delete_access_token_entries(env, access_token).await?;
+    }
+    delete_login_history(env, &user_data.username).await?;
+    AuditSink::from_env(env)?.delete_user_events(user_data.id()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"token lookup secret";
+
+    fn user(username: &str) -> UserData {
+        UserData {
+            username: username.to_string(),
+            password_hash: String::new(),
+            created_at: 0,
+            jwt_secret: String::new(),
+            jwt_version: 1,
+            access_tokens: Vec::new(),
+            pending_deletion: None,
+            account_id: String::new(),
+        }
+    }
+
+    fn pending_user(purge_at: i64) -> UserData {
+        let mut user_data = user("alice");
+        schedule_deletion(&mut user_data, purge_at - 100, 100);
+        user_data
+    }
+
+    #[test]
+    fn schedule_deletion_marks_the_user() {
+        let mut user_data = user("alice");
+        let pending = schedule_deletion(&mut user_data, 1000, 3600);
+
+        assert_eq!((pending.requested_at, pending.purge_at), (1000, 4600));
+        assert_eq!(user_data.pending_deletion.map(|pending| pending.purge_at), Some(4600));
+    }
+
+    #[test]
+    fn purge_index_keys_name_the_user() {
+        assert_eq!(PURGE_INDEX.key(1_000_000_000, "adam"), "pending_deletion:001000000000:adam");
+    }
+
+    #[test]
+    fn restore_token_round_trip() {
+        let user_data = pending_user(5000);
+        let token = generate_restore_token(SECRET, &user_data, 5000);
+
+        assert_eq!(restore_token_username(&token).as_deref(), Some("alice"));
+        assert!(verify_restore_token(SECRET, &user_data, &token, 4999));
+    }
+
+    #[test]
+    fn restore_token_expires_at_purge_time() {
+        let user_data = pending_user(5000);
+        let token = generate_restore_token(SECRET, &user_data, 5000);
+
+        assert!(!verify_restore_token(SECRET, &user_data, &token, 5000));
+    }
+
+    #[test]
+    fn restore_token_only_restores_its_own_deletion() {
+        let user_data = pending_user(5000);
+        let token = generate_restore_token(SECRET, &user_data, 5000);
+
+        let mut rescheduled = pending_user(6000);
+        assert!(!verify_restore_token(SECRET, &rescheduled, &token, 4000));
+
+        rescheduled.pending_deletion = user_data.pending_deletion.clone();
+        rescheduled.jwt_version += 1;
+        assert!(!verify_restore_token(SECRET, &rescheduled, &token, 4000));
+
+        assert!(!verify_restore_token(SECRET, &user("alice"), &token, 4000));
+        assert!(!verify_restore_token(b"other secret", &user_data, &token, 4000));
+    }
+
+    #[test]
+    fn malformed_restore_tokens_are_rejected() {
+        let user_data = pending_user(5000);
+        let token = generate_restore_token(SECRET, &user_data, 5000);
+
+        assert!(!verify_restore_token(SECRET, &user_data, &format!("{}.extra", token), 4000));
+        assert!(!verify_restore_token(SECRET, &user_data, "", 4000));
+        assert_eq!(restore_token_username("not base64!"), None);
+    }
+}
@@ -0,0 +1,440 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use worker::*;
+use chrono::Utc;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::token_lookup::to_hex;
+use crate::client::{client_ip, client_country, client_user_agent, truncate_json};
+use crate::config::env_parse;
+use crate::auth::UserData;
+
+// Security audit log. main() records one event for every state-changing route, handlers only fill
+// in what main cannot know (the user, why a 200 response was still a failure). AUDIT_SINK picks
+// where events go:
+//
+//     kv (default)  USERS_KV, kept for AUDIT_RETENTION_DAYS, queryable via /me/audit and /admin/audit
+//     queue         the AUDIT_QUEUE producer binding, for shipping to an external store
+//     memory        per-isolate buffer, for local development and tests
+//     none          audit logging disabled
+//
+// Events are stored as KV metadata under keys that sort newest first, so a query is a single list
+// call. Per-user keys use the account id (UserData::id), which survives renames and is never
+// handed to a later owner of the username, hex encoded so that no id (":" included) can reach
+// into another user's prefix. Purging an account deletes its events.
+
+const USER_PREFIX: &str = "audit:user:";
+const ALL_PREFIX: &str = "audit:all:";
+
+const DEFAULT_RETENTION_DAYS: u64 = 90;
+// Events must fit in KV's 1024 bytes of metadata (the User-Agent is capped at 256 by
+// client_user_agent), limits are JSON encoded bytes
+const MAX_METADATA_BYTES: usize = 1024;
+const MAX_USER_ID_BYTES: usize = 128;
+const MAX_IP_BYTES: usize = 64;
+const MAX_REASON_BYTES: usize = 64;
+const MEMORY_SINK_CAPACITY: usize = 1000;
+pub const DEFAULT_QUERY_LIMIT: u64 = 50;
+pub const MAX_QUERY_LIMIT: u64 = 1000;
+
+// Subtracted from the millisecond timestamp so that KV's lexicographic order is newest first
+const INVERTED_TIMESTAMP_BASE: i64 = 9_999_999_999_999;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+    Login,
+    Register,
+    Logout,
+    AccountUpdate,
+    UsernameChange,
+    PasswordChange,
+    AccountDelete,
+    AccountRestore,
+    DataExport,
+    DeviceCodeIssue,
+    DeviceApprove,
+    DeviceDeny,
+    DeviceTokenIssue,
+    AccessTokenCreate,
+    AccessTokenRevoke,
+    MasterKeyReencrypt,
+    SigningKeyRotate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEvent {
+    pub id: String,
+    pub timestamp: i64, // Milliseconds since the epoch
+    pub event_type: AuditEventType,
+    pub user_id: Option<String>,  // Account id, only once the account is known
+    #[serde(default)]
+    pub username: Option<String>, // As named in the request, also for unknown users
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub country: Option<String>,
+    pub outcome: AuditOutcome,
+    pub reason: Option<String>, // Error code on failure
+    pub request_id: String,
+}
+
+// Collects an event while a request is handled. Handlers get a shared reference, so the parts
+// they fill in are behind cells.
+pub struct AuditRecorder {
+    event_type: Cell<Option<AuditEventType>>,
+    user_id: RefCell<Option<String>>,
+    username: RefCell<Option<String>>,
+    failure: RefCell<Option<String>>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    country: Option<String>,
+    request_id: String,
+}
+
+impl AuditRecorder {
+    // `event_type` is the route's default, None for routes that are not audited
+    pub fn new(req: &Request, request_id: &str, event_type: Option<AuditEventType>) -> Self {
+        AuditRecorder {
+            event_type: Cell::new(event_type),
+            user_id: RefCell::new(None),
+            username: RefCell::new(None),
+            failure: RefCell::new(None),
+            ip: client_ip(req).map(|ip| truncate_json(&ip, MAX_IP_BYTES)),
+            user_agent: client_user_agent(req),
+            country: client_country(req),
+            request_id: request_id.to_string(),
+        }
+    }
+
+    // The account the request acted on, the event is then listed under it
+    pub fn set_user(&self, user_data: &UserData) {
+        *self.user_id.borrow_mut() = Some(audit_user_id(user_data.id()));
+        self.set_username(&user_data.username);
+    }
+
+    // The username a request named before (or without) the account being found
+    pub fn set_username(&self, username: &str) {
+        *self.username.borrow_mut() = Some(audit_user_id(username));
+    }
+
+    // Replace the route's default event type once the handler knows more
+    pub fn set_event_type(&self, event_type: AuditEventType) {
+        self.event_type.set(Some(event_type));
+    }
+
+    // A request that failed without returning an error (e.g. {"success": false} bodies)
+    pub fn fail(&self, reason: &str) {
+        *self.failure.borrow_mut() = Some(truncate_json(reason, MAX_REASON_BYTES));
+    }
+
+    // Nothing worth recording happened, e.g. a device client polling a pending grant
+    pub fn skip(&self) {
+        self.event_type.set(None);
+    }
+
+    // The finished event, `error_code` is set when the handler returned an error
+    pub fn finish(self, error_code: Option<&str>) -> Option<AuditEvent> {
+        let event_type = self.event_type.get()?;
+        let reason = error_code
+            .map(|code| truncate_json(code, MAX_REASON_BYTES))
+            .or(self.failure.into_inner());
+
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+
+        Some(AuditEvent {
+            id: to_hex(&id),
+            timestamp: Utc::now().timestamp_millis(),
+            event_type,
+            user_id: self.user_id.into_inner(),
+            username: self.username.into_inner(),
+            ip: self.ip,
+            user_agent: self.user_agent,
+            country: self.country,
+            outcome: if reason.is_some() { AuditOutcome::Failure } else { AuditOutcome::Success },
+            reason,
+            request_id: self.request_id,
+        })
+    }
+}
+
+// The user id as recorded, also applied to query arguments so both sinks match on the same value
+fn audit_user_id(user_id: &str) -> String {
+    truncate_json(user_id, MAX_USER_ID_BYTES)
+}
+
+// At most 11 + 2 * 128 + 1 bytes, well inside KV's 512 byte keys together with the suffix
+fn user_key_prefix(user_id: &str) -> String {
+    format!("{}{}:", USER_PREFIX, to_hex(audit_user_id(user_id).as_bytes()))
+}
+
+// The fields are bounded when recorded, this only guards against a field added without a limit
+fn fit_metadata(event: &AuditEvent) -> std::result::Result<AuditEvent, Box<dyn std::error::Error>> {
+    let mut event = event.clone();
+    if serde_json::to_string(&event)?.len() > MAX_METADATA_BYTES {
+        event.user_agent = None;
+    }
+    if serde_json::to_string(&event)?.len() > MAX_METADATA_BYTES {
+        return Err(format!("audit event {} does not fit in KV metadata", event.id).into());
+    }
+    Ok(event)
+}
+
+thread_local! {
+    static MEMORY_SINK: RefCell<VecDeque<AuditEvent>> = const { RefCell::new(VecDeque::new()) };
+}
+
+pub enum AuditSink {
+    Kv { kv: kv::KvStore, ttl_seconds: u64 },
+    Queue(Queue),
+    Memory,
+    None,
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    pub cursor: Option<String>,
+}
+
+impl AuditSink {
+    pub fn from_env(env: &Env) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let sink = env.var("AUDIT_SINK").map(|v| v.to_string()).unwrap_or_default();
+        match sink.as_str() {
+            "queue" => Ok(AuditSink::Queue(env.queue("AUDIT_QUEUE")?)),
+            "memory" => Ok(AuditSink::Memory),
+            "none" => Ok(AuditSink::None),
+            _ => {
+                let retention_days = env_parse(env, "AUDIT_RETENTION_DAYS", DEFAULT_RETENTION_DAYS).max(1);
+                Ok(AuditSink::Kv {
+                    kv: env.kv("USERS_KV")?,
+                    ttl_seconds: retention_days * 24 * 3600,
+                })
+            }
+        }
+    }
+
+    pub async fn write(&self, event: &AuditEvent) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        match self {
+            AuditSink::Kv { kv, ttl_seconds } => {
+                let suffix = format!("{:013}:{}", INVERTED_TIMESTAMP_BASE - event.timestamp, event.id);
+                let metadata = fit_metadata(event)?;
+                let mut keys = vec![format!("{}{}", ALL_PREFIX, suffix)];
+                if let Some(user_id) = &event.user_id {
+                    keys.push(format!("{}{}", user_key_prefix(user_id), suffix));
+                }
+                for key in keys {
+                    kv.put(&key, "")?
+                        .metadata(&metadata)?
+                        .expiration_ttl(*ttl_seconds)
+                        .execute()
+                        .await?;
+                }
+            }
+            AuditSink::Queue(queue) => queue.send(event).await?,
+            AuditSink::Memory => MEMORY_SINK.with(|events| {
+                let mut events = events.borrow_mut();
+                if events.len() >= MEMORY_SINK_CAPACITY {
+                    events.pop_back();
+                }
+                events.push_front(event.clone());
+            }),
+            AuditSink::None => {}
+        }
+        Ok(())
+    }
+
+    // Newest events first, for one user or everyone. None means this sink cannot be queried.
+    pub async fn query(
+        &self,
+        user_id: Option<&str>,
+        limit: u64,
+        cursor: Option<String>,
+    ) -> std::result::Result<Option<AuditPage>, Box<dyn std::error::Error>> {
+        let limit = limit.clamp(1, MAX_QUERY_LIMIT);
+        match self {
+            AuditSink::Kv { kv, .. } => {
+                let prefix = match user_id {
+                    Some(user_id) => user_key_prefix(user_id),
+                    None => ALL_PREFIX.to_string(),
+                };
+                let mut list = kv.list().prefix(prefix).limit(limit);
+                if let Some(cursor) = cursor {
+                    list = list.cursor(cursor);
+                }
+                let page = list.execute().await?;
+
+                let events = page
+                    .keys
+                    .into_iter()
+                    .filter_map(|key| key.metadata)
+                    .filter_map(|metadata| serde_json::from_value(metadata).ok())
+                    .collect();
+                Ok(Some(AuditPage {
+                    events,
+                    cursor: if page.list_complete { None } else { page.cursor },
+                }))
+            }
+            AuditSink::Memory => {
+                // The cursor is an offset into the buffer
+                let offset: usize = cursor.and_then(|cursor| cursor.parse().ok()).unwrap_or(0);
+                let (events, more) = MEMORY_SINK.with(|events| {
+                    let mut matching = events
+                        .borrow()
+                        .iter()
+                        .filter(|event| user_id.is_none() || event.user_id == user_id.map(audit_user_id))
+                        .skip(offset)
+                        .take(limit as usize + 1)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    let more = matching.len() > limit as usize;
+                    matching.truncate(limit as usize);
+                    (matching, more)
+                });
+                let cursor = more.then(|| (offset + events.len()).to_string());
+                Ok(Some(AuditPage { events, cursor }))
+            }
+            AuditSink::Queue(_) | AuditSink::None => Ok(None),
+        }
+    }
+
+    // Remove a user's events, from both their own listing and the one of all users. Events sent
+    // to a queue are the external store's to delete.
+    pub async fn delete_user_events(&self, user_id: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        match self {
+            AuditSink::Kv { kv, .. } => {
+                let prefix = user_key_prefix(user_id);
+                let mut cursor = None;
+                loop {
+                    let mut list = kv.list().prefix(prefix.clone()).limit(MAX_QUERY_LIMIT);
+                    if let Some(cursor) = cursor.take() {
+                        list = list.cursor(cursor);
+                    }
+                    let page = list.execute().await?;
+
+                    for key in &page.keys {
+                        if let Some(suffix) = key.name.strip_prefix(&prefix) {
+                            kv.delete(&format!("{}{}", ALL_PREFIX, suffix)).await?;
+                        }
+                        kv.delete(&key.name).await?;
+                    }
+                    if page.list_complete || page.cursor.is_none() {
+                        break;
+                    }
+                    cursor = page.cursor;
+                }
+            }
+            AuditSink::Memory => MEMORY_SINK.with(|events| {
+                let user_id = audit_user_id(user_id);
+                events.borrow_mut().retain(|event| event.user_id.as_deref() != Some(user_id.as_str()));
+            }),
+            AuditSink::Queue(_) | AuditSink::None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    // Characters whose JSON encoding is longest relative to their count
+    fn worst_case_inputs() -> Vec<String> {
+        vec![
+            "\u{1}".repeat(1000),
+            "\\".repeat(1000),
+            "\"".repeat(1000),
+            "é".repeat(1000),
+            "😀".repeat(1000),
+            "a\u{1}é😀\\".repeat(200),
+        ]
+    }
+
+    fn largest_event(text: &str) -> AuditEvent {
+        AuditEvent {
+            id: "f".repeat(16),
+            timestamp: INVERTED_TIMESTAMP_BASE,
+            event_type: AuditEventType::MasterKeyReencrypt,
+            user_id: Some(audit_user_id(text)),
+            username: Some(audit_user_id(text)),
+            ip: Some(truncate_json(text, MAX_IP_BYTES)),
+            user_agent: Some(truncate_json(text, crate::client::MAX_USER_AGENT_BYTES)),
+            country: Some("XX".to_string()),
+            outcome: AuditOutcome::Failure,
+            reason: Some(truncate_json(text, MAX_REASON_BYTES)),
+            request_id: "r".repeat(128),
+        }
+    }
+
+    #[test]
+    fn largest_event_fits_in_kv_metadata() {
+        for text in worst_case_inputs() {
+            let event = largest_event(&text);
+            let metadata = serde_json::to_string(&event).unwrap();
+            assert!(metadata.len() <= MAX_METADATA_BYTES, "{} bytes", metadata.len());
+            assert!(fit_metadata(&event).unwrap().user_agent.is_some());
+        }
+    }
+
+    #[test]
+    fn largest_user_key_fits_in_kv_key() {
+        for text in worst_case_inputs() {
+            let suffix = format!("{:013}:{}", 0, "f".repeat(16));
+            let key = format!("{}{}", user_key_prefix(&text), suffix);
+            assert!(key.len() <= 512, "{} bytes", key.len());
+        }
+    }
+
+    #[test]
+    fn user_ids_cannot_reach_into_other_prefixes() {
+        assert!(!user_key_prefix("alice:x").starts_with(&user_key_prefix("alice")));
+        assert_eq!(user_key_prefix("alice"), format!("{}616c696365:", USER_PREFIX));
+    }
+
+    // The memory sink never waits on anything, one poll finishes it
+    fn run<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("memory sink operation did not complete"),
+        }
+    }
+
+    fn event_of(user_id: &str) -> AuditEvent {
+        AuditEvent {
+            user_id: Some(user_id.to_string()),
+            username: Some("alice".to_string()),
+            ..largest_event("x")
+        }
+    }
+
+    #[test]
+    fn deleting_a_user_keeps_other_users_events() {
+        let sink = AuditSink::Memory;
+        run(sink.write(&event_of("3f9a0c1d2e4b5a69"))).unwrap();
+        run(sink.write(&event_of("alice"))).unwrap();
+
+        run(sink.delete_user_events("3f9a0c1d2e4b5a69")).unwrap();
+
+        let remaining = |user_id| run(sink.query(Some(user_id), DEFAULT_QUERY_LIMIT, None)).unwrap().unwrap().events.len();
+        assert_eq!(remaining("3f9a0c1d2e4b5a69"), 0);
+        assert_eq!(remaining("alice"), 1);
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        assert_eq!(truncate_json("ééé", 5), "éé");
+        assert_eq!(truncate_json("a\"b", 2), "a");
+        assert_eq!(truncate_json("a\u{1}b", 6), "a");
+        assert_eq!(truncate_json("short", 256), "short");
+    }
+}
@@ -1,9 +1,11 @@
 use serde::{Deserialize, Serialize};
+use crate::access_tokens::PersonalAccessToken;
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
     pub user: String,
     pub password: String,
+    pub scope: Option<String>, // Space separated scopes, defaults to DEFAULT_TOKEN_SCOPES
 }
 
 #[derive(Serialize)]
@@ -12,6 +14,11 @@ pub struct LoginResponse {
     pub token: Option<String>,
     pub message: String,
     pub expires_in: i64, // Duration in seconds
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csrf_token: Option<String>, // Cookie sessions only, send it back in X-CSRF-Token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_token: Option<String>, // Risk-based challenges only, send it back in X-Device-Token
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,6 +27,12 @@ pub struct Claims {
     pub exp: usize,  // Expiration time
     pub iat: usize,  // Issued at
     pub ver: u32,    // JWT version for invalidation
+    #[serde(default)]
+    pub scope: String, // Space separated scopes granted to this token
+    #[serde(default)]
+    pub auth_time: usize, // When the user last proved their password (0 if never, e.g. device grants)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub csrf: String, // CSRF token of a cookie session, empty for bearer tokens
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,18 +42,97 @@ pub struct UserData {
     pub created_at: i64,
     pub jwt_secret: String, // Base64 encoded 512-bit (64 bytes) unique JWT secret
     pub jwt_version: u32,   // Version to invalidate tokens when rotated
+    #[serde(default)]
+    pub access_tokens: Vec<PersonalAccessToken>, // Personal access tokens (hashes only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_deletion: Option<PendingDeletion>, // Set while a deleted account can still be restored
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub account_id: String, // Random and never changed, empty for accounts older than account ids
+}
+
+impl UserData {
+    // Key for records that belong to the account rather than to whoever holds the username
+    // (audit events). Older accounts use their username until their first rename, which keeps
+    // it as their account id.
+    pub fn id(&self) -> &str {
+        if self.account_id.is_empty() {
+            &self.username
+        } else {
+            &self.account_id
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingDeletion {
+    pub requested_at: i64,
+    pub purge_at: i64, // The account is hard-deleted by the first purge job after this time
+}
+
+// Public view of a user, never includes password_hash or jwt_secret
+#[derive(Serialize)]
+pub struct UserProfile {
+    pub username: String,
+    pub created_at: i64,
+}
+
+impl From<&UserData> for UserProfile {
+    fn from(user_data: &UserData) -> Self {
+        UserProfile {
+            username: user_data.username.clone(),
+            created_at: user_data.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProfileResponse {
+    pub success: bool,
+    pub user: UserProfile,
+}
+
+// How the caller proved who they are
+pub enum AuthMethod {
+    Jwt,
+    AccessToken,
+}
+
+// Identity and permissions resolved from the Authorization header
+pub struct AuthContext {
+    pub username: String,
+    pub scope: String,
+    pub method: AuthMethod,
+    pub auth_time: Option<i64>, // Last password authentication, None for access tokens
+    pub csrf: Option<String>,   // Set when authenticated through the session cookie
 }
 
 #[derive(Serialize)]
 pub struct DeleteResponse {
     pub success: bool,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purge_at: Option<i64>, // When the account will be hard-deleted, absent if it already was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restore_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restore_url: Option<String>, // Only with ACCOUNT_RESTORE_URI
+}
+
+#[derive(Deserialize, Default)]
+pub struct RestoreAccountRequest {
+    pub token: String,
 }
 
 #[derive(Deserialize)]
 pub struct UpdateUserRequest {
     pub new_username: Option<String>,
     pub new_password: Option<String>,
+    pub current_password: Option<String>, // Required unless the token's auth_time is recent
+}
+
+#[derive(Deserialize, Default)]
+pub struct DeleteUserRequest {
+    pub current_password: Option<String>, // Required unless the token's auth_time is recent
 }
 
 #[derive(Serialize)]
@@ -50,3 +142,38 @@ pub struct UpdateUserResponse {
     pub new_token: Option<String>, // New JWT token if username changed
     pub expires_in: Option<i64>,   // Token expiration in seconds
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, account_id: &str) -> UserData {
+        UserData {
+            username: username.to_string(),
+            password_hash: String::new(),
+            created_at: 0,
+            jwt_secret: String::new(),
+            jwt_version: 1,
+            access_tokens: Vec::new(),
+            pending_deletion: None,
+            account_id: account_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn account_id_does_not_follow_the_username() {
+        assert_eq!(user("alice", "3f9a0c1d2e4b5a69").id(), "3f9a0c1d2e4b5a69");
+        assert_eq!(user("bob", "3f9a0c1d2e4b5a69").id(), "3f9a0c1d2e4b5a69");
+    }
+
+    #[test]
+    fn older_accounts_fall_back_to_their_username() {
+        assert_eq!(user("alice", "").id(), "alice");
+
+        let stored: UserData = serde_json::from_str(
+            r#"{"username":"alice","password_hash":"","created_at":0,"jwt_secret":"","jwt_version":1}"#,
+        ).unwrap();
+        assert_eq!(stored.id(), "alice");
+        assert!(!serde_json::to_string(&stored).unwrap().contains("account_id"));
+    }
+}
@@ -0,0 +1,74 @@
+use worker::*;
+use worker::js_sys::Reflect;
+use worker::wasm_bindgen::JsValue;
+
+// What a request tells about the client behind it: IP, User-Agent and the approximate location
+// Cloudflare adds in request.cf. Used by risk scoring, audit events and the login history.
+
+// A property of a JS object, None when it is missing, undefined or null
+pub fn js_property(object: &JsValue, name: &str) -> Option<JsValue> {
+    Reflect::get(object, &JsValue::from_str(name))
+        .ok()
+        .filter(|value| !value.is_undefined() && !value.is_null())
+}
+
+// A request.cf field, missing in local development
+fn cf_property(req: &Request, name: &str) -> Option<JsValue> {
+    js_property(req.inner(), "cf").and_then(|cf| js_property(&cf, name))
+}
+
+pub fn client_ip(req: &Request) -> Option<String> {
+    req.headers().get("CF-Connecting-IP").ok().flatten()
+}
+
+// Budget for the User-Agent wherever it is stored, as JSON encoded bytes
+pub const MAX_USER_AGENT_BYTES: usize = 256;
+
+// The client's User-Agent, shortened to fit next to other fields in size limited records
+pub fn client_user_agent(req: &Request) -> Option<String> {
+    req.headers()
+        .get("User-Agent")
+        .ok()
+        .flatten()
+        .map(|user_agent| truncate_json(&user_agent, MAX_USER_AGENT_BYTES))
+}
+
+// The longest prefix of `text` whose JSON string encoding (without the quotes) fits in
+// `max_bytes`. KV limits are in bytes, and escaping can make one character take up to six.
+pub fn truncate_json(text: &str, max_bytes: usize) -> String {
+    let mut encoded_len = 0;
+    let mut end = 0;
+    for (index, c) in text.char_indices() {
+        encoded_len += match c {
+            '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+            c if (c as u32) < 0x20 => 6,
+            c => c.len_utf8(),
+        };
+        if encoded_len > max_bytes {
+            break;
+        }
+        end = index + c.len_utf8();
+    }
+    text[..end].to_string()
+}
+
+pub fn client_country(req: &Request) -> Option<String> {
+    cf_property(req, "country").and_then(|country| country.as_string())
+}
+
+// Approximate location for people to read, e.g. in the login history
+pub struct ClientLocation {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+}
+
+pub fn client_location(req: &Request) -> ClientLocation {
+    let cf_string = |name: &str| cf_property(req, name).and_then(|value| value.as_string());
+
+    ClientLocation {
+        country: cf_string("country"),
+        region: cf_string("region"),
+        city: cf_string("city"),
+    }
+}
@@ -0,0 +1,35 @@
+use std::str::FromStr;
+use worker::*;
+
+// Reading optional settings from [vars]. Unset, blank and unparsable values all fall back to
+// the default, so a typo never takes the Worker down.
+
+// KV rejects TTLs below 60 seconds
+pub const MIN_KV_TTL_SECONDS: u64 = 60;
+
+// A variable trimmed, None when unset or blank
+pub fn env_string(env: &Env, name: &str) -> Option<String> {
+    env.var(name)
+        .map(|value| value.to_string().trim().to_string())
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+pub fn env_parse<T: FromStr>(env: &Env, name: &str, default: T) -> T {
+    env_string(env, name)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// A comma separated list, items trimmed and empty ones dropped
+pub fn env_list(env: &Env, name: &str) -> Vec<String> {
+    env_string(env, name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
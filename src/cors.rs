@@ -0,0 +1,150 @@
+use worker::*;
+use crate::config::{env_parse, env_string};
+
+// CORS policy configured from env vars:
+//
+//     CORS_ALLOWED_ORIGINS    comma separated, "https://app.example.com" or "https://*.example.com"
+//                             ("*" allows any origin, the default for backwards compatibility)
+//     CORS_ALLOWED_METHODS    defaults to "GET, POST, DELETE, PATCH, OPTIONS"
+//     CORS_ALLOWED_HEADERS    defaults to "Content-Type, cf-turnstile-response, Authorization, X-CSRF-Token, X-Device-Token, X-Current-Password"
+//     CORS_ALLOW_CREDENTIALS  "true" to send Access-Control-Allow-Credentials (ignored with "*")
+//     CORS_MAX_AGE            preflight cache lifetime in seconds, defaults to 86400
+
+const DEFAULT_ALLOWED_METHODS: &str = "GET, POST, DELETE, PATCH, OPTIONS";
+const DEFAULT_ALLOWED_HEADERS: &str = "Content-Type, cf-turnstile-response, Authorization, X-CSRF-Token, X-Device-Token, X-Current-Password";
+const DEFAULT_MAX_AGE: u32 = 86400;
+// Response headers scripts on other origins may read
+const EXPOSED_HEADERS: &str = "X-Request-ID";
+
+pub struct CorsPolicy {
+    allow_any_origin: bool,
+    allowed_origins: Vec<String>,
+    allowed_methods: String,
+    allowed_headers: String,
+    allow_credentials: bool,
+    max_age: u32,
+}
+
+// "https://*.example.com" matches "https://app.example.com" and "https://a.b.example.com",
+// but not "https://example.com" itself (list it separately). Ports must match exactly.
+// Schemes and hosts are case-insensitive, so both sides are compared lowercased.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let origin = origin.to_ascii_lowercase();
+    match pattern.split_once("*.") {
+        Some((scheme, domain)) => origin
+            .strip_prefix(scheme)
+            .and_then(|host| host.strip_suffix(domain))
+            .and_then(|host| host.strip_suffix('.'))
+            .is_some_and(|subdomain| {
+                !subdomain.is_empty()
+                    && subdomain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            }),
+        None => pattern == origin,
+    }
+}
+
+impl CorsPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        let origins = env_string(env, "CORS_ALLOWED_ORIGINS").unwrap_or_else(|| "*".to_string());
+        let allowed_origins: Vec<String> = origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        // Credentials are never offered to every origin, that would let any site act as the user
+        let allow_any_origin = allowed_origins.iter().any(|origin| origin == "*");
+
+        CorsPolicy {
+            allow_any_origin,
+            allowed_origins,
+            allowed_methods: env_string(env, "CORS_ALLOWED_METHODS").unwrap_or_else(|| DEFAULT_ALLOWED_METHODS.to_string()),
+            allowed_headers: env_string(env, "CORS_ALLOWED_HEADERS").unwrap_or_else(|| DEFAULT_ALLOWED_HEADERS.to_string()),
+            allow_credentials: !allow_any_origin
+                && env_string(env, "CORS_ALLOW_CREDENTIALS").is_some_and(|value| value == "true"),
+            max_age: env_parse(env, "CORS_MAX_AGE", DEFAULT_MAX_AGE),
+        }
+    }
+
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.allow_any_origin || self.allowed_origins.iter().any(|pattern| origin_matches(pattern, origin))
+    }
+
+    // Headers for an actual (non-preflight) response. Disallowed origins get no CORS headers,
+    // so the browser refuses to expose the response.
+    pub fn apply(&self, headers: &mut Headers, origin: Option<&str>) {
+        if self.allow_any_origin {
+            headers.set("Access-Control-Allow-Origin", "*").ok();
+            headers.set("Access-Control-Expose-Headers", EXPOSED_HEADERS).ok();
+            return;
+        }
+
+        // The response depends on the Origin header, caches must key on it
+        headers.append("Vary", "Origin").ok();
+
+        if let Some(origin) = origin.filter(|origin| self.is_origin_allowed(origin)) {
+            headers.set("Access-Control-Allow-Origin", origin).ok();
+            headers.set("Access-Control-Expose-Headers", EXPOSED_HEADERS).ok();
+            if self.allow_credentials {
+                headers.set("Access-Control-Allow-Credentials", "true").ok();
+            }
+        }
+    }
+
+    // Headers for a preflight response, None when the origin is not allowed
+    pub fn preflight_headers(&self, origin: Option<&str>) -> Option<Headers> {
+        if let Some(origin) = origin {
+            if !self.is_origin_allowed(origin) {
+                return None;
+            }
+        }
+
+        let mut headers = Headers::new();
+        self.apply(&mut headers, origin);
+        headers.set("Access-Control-Allow-Methods", &self.allowed_methods).ok();
+        headers.set("Access-Control-Allow-Headers", &self.allowed_headers).ok();
+        headers.set("Access-Control-Max-Age", &self.max_age.to_string()).ok();
+        Some(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_origins_match_case_insensitively() {
+        assert!(origin_matches("https://app.example.com", "https://app.example.com"));
+        assert!(origin_matches("https://app.example.com", "https://APP.example.com"));
+        assert!(!origin_matches("https://app.example.com", "http://app.example.com"));
+        assert!(!origin_matches("https://app.example.com", "https://app.example.com:8443"));
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        let pattern = "https://*.example.com";
+        assert!(origin_matches(pattern, "https://app.example.com"));
+        assert!(origin_matches(pattern, "https://a.b.example.com"));
+        assert!(!origin_matches(pattern, "https://example.com"));
+        assert!(!origin_matches(pattern, "https://.example.com"));
+        assert!(!origin_matches(pattern, "http://app.example.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_case_insensitively() {
+        assert!(origin_matches("https://*.example.com", "https://APP.Example.COM"));
+        assert!(origin_matches("HTTPS://*.EXAMPLE.COM", "https://app.example.com"));
+        assert!(!origin_matches("https://*.Example.com", "https://Example.com"));
+    }
+
+    #[test]
+    fn wildcard_does_not_match_lookalike_hosts() {
+        let pattern = "https://*.example.com";
+        assert!(!origin_matches(pattern, "https://evilexample.com"));
+        assert!(!origin_matches(pattern, "https://app.example.com.evil.net"));
+        assert!(!origin_matches(pattern, "https://evil.net/.example.com"));
+        assert!(!origin_matches(pattern, "https://user@app.example.com"));
+        assert!(!origin_matches(pattern, "https://app.example.com:8443"));
+    }
+}
@@ -0,0 +1,149 @@
+use serde::Serialize;
+use worker::*;
+use crate::auth::{UserData, UserProfile, AuthContext, AuthMethod, PendingDeletion};
+use crate::access_tokens::AccessTokenSummary;
+use crate::audit::{AuditEvent, AuditSink, MAX_QUERY_LIMIT};
+use crate::login_history::{LoginRecord, get_login_history};
+
+// GET /me/export: everything held about one user as a single JSON document, for data access
+// requests. Built from the same public views the other /me routes return, so password hashes,
+// JWT secrets and access token hashes never appear in it.
+
+pub const CURRENT_PASSWORD_HEADER: &str = "X-Current-Password";
+
+// Audit events beyond this many are left out of the archive, see audit_events_truncated
+const MAX_EXPORTED_AUDIT_EVENTS: usize = 10_000;
+
+#[derive(Serialize)]
+pub struct CurrentSession {
+    pub method: &'static str, // "jwt" or "access_token"
+    pub cookie: bool,         // Authenticated through the session cookie
+    pub scope: String,
+    pub auth_time: Option<i64>,
+}
+
+impl From<&AuthContext> for CurrentSession {
+    fn from(auth: &AuthContext) -> Self {
+        CurrentSession {
+            method: match auth.method {
+                AuthMethod::Jwt => "jwt",
+                AuthMethod::AccessToken => "access_token",
+            },
+            cookie: auth.csrf.is_some(),
+            scope: auth.scope.clone(),
+            auth_time: auth.auth_time,
+        }
+    }
+}
+
+// Sessions are stateless JWTs, only the one making the request is known
+#[derive(Serialize)]
+pub struct ExportedSessions {
+    pub current: CurrentSession,
+    pub access_tokens: Vec<AccessTokenSummary>,
+}
+
+#[derive(Serialize)]
+pub struct DataExport {
+    pub success: bool,
+    pub exported_at: i64,
+    pub profile: UserProfile,
+    pub pending_deletion: Option<PendingDeletion>,
+    pub sessions: ExportedSessions,
+    pub login_history: Vec<LoginRecord>,
+    pub audit_events: Option<Vec<AuditEvent>>, // None when AUDIT_SINK cannot be queried
+    pub audit_events_truncated: bool,
+    // The service has no MFA or consent records yet, the keys are kept so the format stays stable
+    pub mfa_enrollments: Vec<serde_json::Value>,
+    pub consents: Vec<serde_json::Value>,
+}
+
+// Every audit event of the user, newest first, up to MAX_EXPORTED_AUDIT_EVENTS
+async fn collect_audit_events(env: &Env, account_id: &str) -> std::result::Result<(Option<Vec<AuditEvent>>, bool), Box<dyn std::error::Error>> {
+    let sink = AuditSink::from_env(env)?;
+    let mut events = Vec::new();
+    let mut cursor: Option<String> = None;
+
+    loop {
+        let Some(page) = sink.query(Some(account_id), MAX_QUERY_LIMIT, cursor.take()).await? else {
+            return Ok((None, false));
+        };
+        events.extend(page.events);
+
+        if events.len() >= MAX_EXPORTED_AUDIT_EVENTS {
+            let truncated = events.len() > MAX_EXPORTED_AUDIT_EVENTS || page.cursor.is_some();
+            events.truncate(MAX_EXPORTED_AUDIT_EVENTS);
+            return Ok((Some(events), truncated));
+        }
+        match page.cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok((Some(events), false)),
+        }
+    }
+}
+
+// `attachment` header naming the file "<username>-export.json". Usernames may hold quotes and
+// non-ASCII characters, so the plain filename gets a sanitized copy and the exact name goes in
+// the RFC 6266 filename* parameter, percent-encoded.
+pub fn content_disposition(username: &str) -> String {
+    let filename = format!("{}-export.json", username);
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+pub async fn build_export(env: &Env, user_data: &UserData, auth: &AuthContext, now: i64) -> std::result::Result<DataExport, Box<dyn std::error::Error>> {
+    let (audit_events, audit_events_truncated) = collect_audit_events(env, user_data.id()).await?;
+
+    Ok(DataExport {
+        success: true,
+        exported_at: now,
+        profile: UserProfile::from(user_data),
+        pending_deletion: user_data.pending_deletion.clone(),
+        sessions: ExportedSessions {
+            current: CurrentSession::from(auth),
+            access_tokens: user_data.access_tokens.iter().map(AccessTokenSummary::from).collect(),
+        },
+        login_history: get_login_history(env, &user_data.username).await?,
+        audit_events,
+        audit_events_truncated,
+        mfa_enrollments: Vec::new(),
+        consents: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_usernames_keep_their_name() {
+        assert_eq!(
+            content_disposition("alice_01"),
+            "attachment; filename=\"alice_01-export.json\"; filename*=UTF-8''alice_01-export.json"
+        );
+    }
+
+    #[test]
+    fn header_syntax_cannot_be_injected() {
+        let header = content_disposition("a\"; filename=evil.exe; x=\"\r\n");
+        let (fallback, encoded) = header.split_once("; filename*=").unwrap();
+
+        assert_eq!(fallback, "attachment; filename=\"a___filename_evil.exe__x____-export.json\"");
+        assert!(encoded.bytes().all(|b| b.is_ascii_graphic() && !matches!(b, b'"' | b';' | b' ')));
+    }
+
+    #[test]
+    fn non_ascii_usernames_are_percent_encoded() {
+        assert!(content_disposition("jörg").ends_with("filename*=UTF-8''j%C3%B6rg-export.json"));
+    }
+}
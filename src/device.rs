@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use crate::config::MIN_KV_TTL_SECONDS;
+
+// KV key prefixes for pending device authorization grants (RFC 8628)
+const DEVICE_CODE_PREFIX: &str = "device_code:";
+const USER_CODE_PREFIX: &str = "device_user_code:";
+
+// Characters used for user codes: no vowels (avoids words) and no ambiguous digits
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+// Random bytes from here up are dropped: 240 is the largest multiple of the alphabet size that
+// fits in a byte, so every character stays equally likely
+const USER_CODE_BYTE_LIMIT: u8 = (256 / USER_CODE_ALPHABET.len() * USER_CODE_ALPHABET.len()) as u8;
+
+// RFC 8628 section 3.5: each slow_down adds 5 seconds to the polling interval
+const SLOW_DOWN_SECONDS: i64 = 5;
+
+pub const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceGrantStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeviceGrant {
+    pub user_code: String,
+    pub status: DeviceGrantStatus,
+    pub username: Option<String>, // Set once a logged-in user approves the grant
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub interval: i64,            // Minimum polling interval in seconds
+    pub last_polled_at: Option<i64>,
+    #[serde(default)]
+    pub scope: String,            // Scopes the device asked for, granted on approval
+}
+
+impl DeviceGrant {
+    // Past its expiry, whatever its status
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at < now
+    }
+
+    // Only pending grants that have not expired can be approved or denied
+    pub fn awaiting_decision(&self, now: i64) -> bool {
+        self.status == DeviceGrantStatus::Pending && !self.is_expired(now)
+    }
+
+    pub fn approve(&mut self, username: &str) {
+        self.status = DeviceGrantStatus::Approved;
+        self.username = Some(username.to_string());
+    }
+
+    pub fn deny(&mut self) {
+        self.status = DeviceGrantStatus::Denied;
+    }
+
+    // Record a poll of a pending grant, returning true when the client polled before the interval
+    // was up. The interval then grows for every later poll.
+    pub fn record_poll(&mut self, now: i64) -> bool {
+        let too_fast = self
+            .last_polled_at
+            .is_some_and(|last| now - last < self.interval);
+
+        if too_fast {
+            self.interval += SLOW_DOWN_SECONDS;
+        }
+        self.last_polled_at = Some(now);
+        too_fast
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct DeviceCodeRequest {
+    pub scope: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i64,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceVerifyRequest {
+    pub user_code: String,
+    pub approve: Option<bool>, // Defaults to true
+}
+
+#[derive(Deserialize, Default)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub device_code: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub scope: String,
+}
+
+// Generate an opaque 256-bit device code for the polling client
+pub fn generate_device_code() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Generate a short user code in the form XXXX-XXXX for manual entry
+pub fn generate_user_code() -> String {
+    let random_bytes = std::iter::repeat_with(|| {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        bytes
+    });
+    user_code_from_bytes(random_bytes.flatten())
+}
+
+fn user_code_from_bytes(bytes: impl Iterator<Item = u8>) -> String {
+    let chars: String = bytes
+        .filter(|b| *b < USER_CODE_BYTE_LIMIT)
+        .take(USER_CODE_LENGTH)
+        .map(|b| USER_CODE_ALPHABET[b as usize % USER_CODE_ALPHABET.len()] as char)
+        .collect();
+    format!("{}-{}", &chars[..4], &chars[4..])
+}
+
+// Normalize user input so "bcdf ghjk" and "BCDF-GHJK" match the same grant
+pub fn normalize_user_code(user_code: &str) -> String {
+    let chars: String = user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if chars.len() == USER_CODE_LENGTH {
+        format!("{}-{}", &chars[..4], &chars[4..])
+    } else {
+        chars
+    }
+}
+
+// RFC 6749 section 5.2 error response used by the token endpoint
+pub fn oauth_error_response(error: &str, description: &str) -> Result<Response> {
+    Response::from_json(&serde_json::json!({
+        "error": error,
+        "error_description": description
+    })).map(|res| res.with_status(400))
+}
+
+pub async fn store_device_grant(
+    env: &Env,
+    device_code: &str,
+    grant: &DeviceGrant,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    let grant_json = serde_json::to_string(grant)?;
+
+    // The grant itself still carries the real expiry
+    let ttl = ((grant.expires_at - chrono::Utc::now().timestamp()).max(0) as u64).max(MIN_KV_TTL_SECONDS);
+
+    kv.put(&format!("{}{}", DEVICE_CODE_PREFIX, device_code), grant_json)?
+        .expiration_ttl(ttl)
+        .execute()
+        .await?;
+    kv.put(&format!("{}{}", USER_CODE_PREFIX, grant.user_code), device_code)?
+        .expiration_ttl(ttl)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn get_device_grant(env: &Env, device_code: &str) -> std::result::Result<DeviceGrant, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    match kv.get(&format!("{}{}", DEVICE_CODE_PREFIX, device_code)).text().await? {
+        Some(grant_json) => Ok(serde_json::from_str(&grant_json)?),
+        None => Err("Device grant not found".into())
+    }
+}
+
+pub async fn get_device_code_for_user_code(env: &Env, user_code: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    match kv.get(&format!("{}{}", USER_CODE_PREFIX, user_code)).text().await? {
+        Some(device_code) => Ok(device_code),
+        None => Err("User code not found".into())
+    }
+}
+
+pub async fn delete_device_grant(env: &Env, device_code: &str, user_code: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    kv.delete(&format!("{}{}", DEVICE_CODE_PREFIX, device_code)).await?;
+    kv.delete(&format!("{}{}", USER_CODE_PREFIX, user_code)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_grant(now: i64) -> DeviceGrant {
+        DeviceGrant {
+            user_code: "BCDF-GHJK".to_string(),
+            status: DeviceGrantStatus::Pending,
+            username: None,
+            created_at: now,
+            expires_at: now + 600,
+            interval: 5,
+            last_polled_at: None,
+            scope: "read".to_string(),
+        }
+    }
+
+    #[test]
+    fn generated_user_codes_use_the_alphabet() {
+        for _ in 0..100 {
+            let user_code = generate_user_code();
+            assert_eq!(user_code.len(), USER_CODE_LENGTH + 1);
+            assert_eq!(&user_code[4..5], "-");
+            assert!(user_code.bytes().filter(|b| *b != b'-').all(|b| USER_CODE_ALPHABET.contains(&b)));
+            assert_eq!(normalize_user_code(&user_code), user_code);
+        }
+    }
+
+    #[test]
+    fn biased_bytes_are_rejected() {
+        assert_eq!(USER_CODE_BYTE_LIMIT, 240);
+        // 240..=255 would favour the first 16 characters, they are skipped
+        let bytes = [240, 255, 0, 1, 2, 3, 250, 19, 20, 239, 21];
+        assert_eq!(user_code_from_bytes(bytes.into_iter()), "BCDF-ZBZC");
+    }
+
+    #[test]
+    fn user_codes_are_normalized() {
+        assert_eq!(normalize_user_code("bcdf ghjk"), "BCDF-GHJK");
+        assert_eq!(normalize_user_code(" BCDF-GHJK "), "BCDF-GHJK");
+        assert_eq!(normalize_user_code("bcdfghjk"), "BCDF-GHJK");
+        assert_eq!(normalize_user_code("bcd-fgh"), "BCDFGH");
+        assert_eq!(normalize_user_code(""), "");
+    }
+
+    #[test]
+    fn only_pending_unexpired_grants_await_a_decision() {
+        let now = 1_000_000;
+        let mut grant = pending_grant(now);
+        assert!(grant.awaiting_decision(now));
+        assert!(grant.awaiting_decision(now + 600));
+        assert!(!grant.awaiting_decision(now + 601));
+        assert!(grant.is_expired(now + 601));
+
+        grant.deny();
+        assert!(grant.status == DeviceGrantStatus::Denied);
+        assert!(!grant.awaiting_decision(now));
+    }
+
+    #[test]
+    fn approval_records_the_approving_user() {
+        let now = 1_000_000;
+        let mut grant = pending_grant(now);
+        grant.approve("alice");
+        assert!(grant.status == DeviceGrantStatus::Approved);
+        assert_eq!(grant.username.as_deref(), Some("alice"));
+        assert!(!grant.awaiting_decision(now));
+    }
+
+    #[test]
+    fn polling_too_fast_slows_the_client_down() {
+        let now = 1_000_000;
+        let mut grant = pending_grant(now);
+        assert!(!grant.record_poll(now));
+        assert!(grant.record_poll(now + 4));
+        assert_eq!(grant.interval, 10);
+        assert_eq!(grant.last_polled_at, Some(now + 4));
+
+        // The longer interval applies from then on
+        assert!(grant.record_poll(now + 13));
+        assert_eq!(grant.interval, 15);
+        assert!(!grant.record_poll(now + 28));
+        assert_eq!(grant.interval, 15);
+    }
+}
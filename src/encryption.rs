@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Key, Nonce};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use serde::Deserialize;
+use worker::*;
+
+// Envelope encryption for secrets stored in KV (per-user JWT secrets, future TOTP secrets).
+// Each value gets its own random data key (DEK); the DEK is wrapped by a versioned master key
+// (KEK) held in a Worker secret. Stored format:
+//
+//     enc:v2:<kek version>:<base64 nonce||wrapped DEK>:<base64 nonce||ciphertext>
+//
+// The associated data is "<context>:<owner>", so a value only decrypts for the field and the
+// record (account id, signing key id) it was written for. enc:v1 values were bound to the context
+// alone; they still decrypt and `rewrap_secret` re-encrypts them as v2. Rotating the master key
+// only needs the DEK re-wrapped.
+
+const ENCRYPTED_PREFIX: &str = "enc:v2:";
+const LEGACY_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+// What a ciphertext is for, bound as associated data so values cannot be swapped between fields
+pub const JWT_SECRET_CONTEXT: &str = "jwt_secret";
+
+#[derive(Deserialize, Default)]
+pub struct ReencryptRequest {
+    pub cursor: Option<String>, // Continue from the cursor returned by the previous call
+    pub limit: Option<u64>,     // Keys scanned per call, defaults to 100
+}
+
+// Master keys by version, loaded from MASTER_KEY_V<n> secrets
+pub struct MasterKeyring {
+    pub current_version: u32,
+    keys: HashMap<u32, [u8; 32]>,
+}
+
+impl MasterKeyring {
+    // MASTER_KEY_VERSION selects the current key; every older MASTER_KEY_V<n> still set is kept
+    // for decryption. Returns None when encryption at rest is not configured.
+    pub fn from_env(env: &Env) -> std::result::Result<Option<Self>, Box<dyn std::error::Error>> {
+        let current_version = match env.var("MASTER_KEY_VERSION") {
+            Ok(version) => version.to_string().parse::<u32>()?,
+            Err(_) => return Ok(None),
+        };
+
+        let mut keys = HashMap::new();
+        for version in 1..=current_version {
+            if let Ok(secret) = env.secret(&format!("MASTER_KEY_V{}", version)) {
+                let bytes = general_purpose::STANDARD.decode(secret.to_string())?;
+                let key: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| format!("MASTER_KEY_V{} must be 32 bytes (base64)", version))?;
+                keys.insert(version, key);
+            }
+        }
+
+        if !keys.contains_key(&current_version) {
+            return Err(format!("MASTER_KEY_V{} is not set", current_version).into());
+        }
+
+        Ok(Some(MasterKeyring { current_version, keys }))
+    }
+
+    #[cfg(test)]
+    pub fn from_keys(current_version: u32, keys: Vec<(u32, [u8; 32])>) -> Self {
+        MasterKeyring { current_version, keys: keys.into_iter().collect() }
+    }
+
+    fn key(&self, version: u32) -> std::result::Result<&[u8; 32], Box<dyn std::error::Error>> {
+        self.keys
+            .get(&version)
+            .ok_or_else(|| format!("Master key version {} is not available", version).into())
+    }
+}
+
+fn seal(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| "Encryption failed")?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn open(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if sealed.len() < NONCE_LEN {
+        return Err("Ciphertext too short".into());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+
+    Ok(cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Decryption failed")?)
+}
+
+// A parsed stored value
+struct Envelope {
+    owner_bound: bool, // false for enc:v1 values
+    version: u32,
+    wrapped_dek: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    fn parse(stored: &str) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let (owner_bound, body) = match (stored.strip_prefix(ENCRYPTED_PREFIX), stored.strip_prefix(LEGACY_PREFIX)) {
+            (Some(body), _) => (true, body),
+            (None, Some(body)) => (false, body),
+            (None, None) => return Err("Value is not encrypted".into()),
+        };
+        let mut parts = body.splitn(3, ':');
+        let (version, wrapped_dek, ciphertext) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(version), Some(wrapped_dek), Some(ciphertext)) => (version, wrapped_dek, ciphertext),
+            _ => return Err("Malformed encrypted value".into()),
+        };
+
+        Ok(Envelope {
+            owner_bound,
+            version: version.parse()?,
+            wrapped_dek: general_purpose::STANDARD.decode(wrapped_dek)?,
+            ciphertext: general_purpose::STANDARD.decode(ciphertext)?,
+        })
+    }
+
+    // Contexts are fixed identifiers without ':', so the owner cannot shift the boundary
+    fn associated_data(&self, context: &str, owner: &str) -> Vec<u8> {
+        if self.owner_bound {
+            format!("{}:{}", context, owner).into_bytes()
+        } else {
+            context.as_bytes().to_vec()
+        }
+    }
+
+    // Values are always written owner-bound
+    fn encode(&self) -> String {
+        format!(
+            "{}{}:{}:{}",
+            ENCRYPTED_PREFIX,
+            self.version,
+            general_purpose::STANDARD.encode(&self.wrapped_dek),
+            general_purpose::STANDARD.encode(&self.ciphertext),
+        )
+    }
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX) || stored.starts_with(LEGACY_PREFIX)
+}
+
+// Encrypt a secret belonging to `owner` under a fresh DEK wrapped by the current master key
+pub fn encrypt_secret(keyring: &MasterKeyring, context: &str, owner: &str, plaintext: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let mut dek = [0u8; 32];
+    OsRng.fill_bytes(&mut dek);
+
+    let mut envelope = Envelope { owner_bound: true, version: keyring.current_version, wrapped_dek: Vec::new(), ciphertext: Vec::new() };
+    let aad = envelope.associated_data(context, owner);
+    envelope.wrapped_dek = seal(keyring.key(keyring.current_version)?, &dek, &aad)?;
+    envelope.ciphertext = seal(&dek, plaintext.as_bytes(), &aad)?;
+    Ok(envelope.encode())
+}
+
+// Decrypt a stored secret of `owner`. Values written before encryption was enabled are returned
+// unchanged.
+pub fn decrypt_secret(keyring: Option<&MasterKeyring>, context: &str, owner: &str, stored: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    if !is_encrypted(stored) {
+        return Ok(stored.to_string());
+    }
+    let keyring = keyring.ok_or("Encrypted value found but no master key is configured")?;
+
+    let envelope = Envelope::parse(stored)?;
+    let aad = envelope.associated_data(context, owner);
+    let dek: [u8; 32] = open(keyring.key(envelope.version)?, &envelope.wrapped_dek, &aad)?
+        .try_into()
+        .map_err(|_| "Invalid data key length")?;
+    let plaintext = open(&dek, &envelope.ciphertext, &aad)?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+// True when a stored value is plaintext, not bound to its owner or wrapped by an older master key
+pub fn needs_reencryption(keyring: &MasterKeyring, stored: &str) -> bool {
+    match Envelope::parse(stored) {
+        Ok(envelope) => !envelope.owner_bound || envelope.version != keyring.current_version,
+        Err(_) => true,
+    }
+}
+
+// Bring a stored value up to the current master key. Owner-bound values only have their DEK
+// re-wrapped, enc:v1 values are re-encrypted for their owner and plaintext values are encrypted
+// for the first time.
+pub fn rewrap_secret(keyring: &MasterKeyring, context: &str, owner: &str, stored: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    if !is_encrypted(stored) {
+        return encrypt_secret(keyring, context, owner, stored);
+    }
+
+    let mut envelope = Envelope::parse(stored)?;
+    if !envelope.owner_bound {
+        let plaintext = decrypt_secret(Some(keyring), context, owner, stored)?;
+        return encrypt_secret(keyring, context, owner, &plaintext);
+    }
+
+    let aad = envelope.associated_data(context, owner);
+    let dek = open(keyring.key(envelope.version)?, &envelope.wrapped_dek, &aad)?;
+    envelope.wrapped_dek = seal(keyring.key(keyring.current_version)?, &dek, &aad)?;
+    envelope.version = keyring.current_version;
+    Ok(envelope.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "per-user jwt secret";
+    const OWNER: &str = "9f86d081884c7d65";
+
+    fn keyring_v1() -> MasterKeyring {
+        MasterKeyring::from_keys(1, vec![(1, [1u8; 32])])
+    }
+
+    fn keyring_v2() -> MasterKeyring {
+        MasterKeyring::from_keys(2, vec![(1, [1u8; 32]), (2, [2u8; 32])])
+    }
+
+    #[test]
+    fn encrypted_secret_decrypts_to_the_plaintext() {
+        let keyring = keyring_v1();
+        let stored = encrypt_secret(&keyring, JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+
+        assert!(is_encrypted(&stored));
+        assert!(!stored.contains(SECRET));
+        assert_eq!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, OWNER, &stored).unwrap(), SECRET);
+        assert!(!needs_reencryption(&keyring, &stored));
+    }
+
+    #[test]
+    fn rewrapped_secret_only_needs_the_new_master_key() {
+        let stored = encrypt_secret(&keyring_v1(), JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+        assert!(needs_reencryption(&keyring_v2(), &stored));
+
+        let rewrapped = rewrap_secret(&keyring_v2(), JWT_SECRET_CONTEXT, OWNER, &stored).unwrap();
+        assert!(!needs_reencryption(&keyring_v2(), &rewrapped));
+
+        let v2_only = MasterKeyring::from_keys(2, vec![(2, [2u8; 32])]);
+        assert_eq!(decrypt_secret(Some(&v2_only), JWT_SECRET_CONTEXT, OWNER, &rewrapped).unwrap(), SECRET);
+        assert!(decrypt_secret(Some(&keyring_v1()), JWT_SECRET_CONTEXT, OWNER, &rewrapped).is_err());
+    }
+
+    #[test]
+    fn wrong_context_fails() {
+        let keyring = keyring_v1();
+        let stored = encrypt_secret(&keyring, JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+
+        assert!(decrypt_secret(Some(&keyring), "signing_key", OWNER, &stored).is_err());
+        assert!(rewrap_secret(&keyring_v2(), "signing_key", OWNER, &stored).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails() {
+        let keyring = keyring_v1();
+        let stored = encrypt_secret(&keyring, JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+
+        let (head, ciphertext) = stored.rsplit_once(':').unwrap();
+        let mut ciphertext = general_purpose::STANDARD.decode(ciphertext).unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 1;
+        let tampered = format!("{}:{}", head, general_purpose::STANDARD.encode(&ciphertext));
+
+        assert!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, OWNER, &tampered).is_err());
+    }
+
+    #[test]
+    fn encrypted_secret_needs_a_keyring() {
+        let stored = encrypt_secret(&keyring_v1(), JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+        assert!(decrypt_secret(None, JWT_SECRET_CONTEXT, OWNER, &stored).is_err());
+    }
+
+    #[test]
+    fn plaintext_passes_through_until_rewrapped() {
+        let keyring = keyring_v1();
+
+        assert_eq!(decrypt_secret(None, JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap(), SECRET);
+        assert_eq!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap(), SECRET);
+        assert!(needs_reencryption(&keyring, SECRET));
+
+        let encrypted = rewrap_secret(&keyring, JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, OWNER, &encrypted).unwrap(), SECRET);
+    }
+
+    #[test]
+    fn secret_only_decrypts_for_its_owner() {
+        let keyring = keyring_v1();
+        let stored = encrypt_secret(&keyring, JWT_SECRET_CONTEXT, OWNER, SECRET).unwrap();
+
+        assert!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, "mallory", &stored).is_err());
+        assert!(rewrap_secret(&keyring_v2(), JWT_SECRET_CONTEXT, "mallory", &stored).is_err());
+    }
+
+    // A value as written before secrets were bound to their owner
+    fn encrypt_v1(keyring: &MasterKeyring, plaintext: &str) -> String {
+        let dek = [7u8; 32];
+        let envelope = Envelope {
+            owner_bound: false,
+            version: keyring.current_version,
+            wrapped_dek: seal(keyring.key(keyring.current_version).unwrap(), &dek, JWT_SECRET_CONTEXT.as_bytes()).unwrap(),
+            ciphertext: seal(&dek, plaintext.as_bytes(), JWT_SECRET_CONTEXT.as_bytes()).unwrap(),
+        };
+        envelope.encode().replacen(ENCRYPTED_PREFIX, LEGACY_PREFIX, 1)
+    }
+
+    #[test]
+    fn v1_secret_is_rewrapped_for_its_owner() {
+        let keyring = keyring_v1();
+        let legacy = encrypt_v1(&keyring, SECRET);
+
+        assert!(is_encrypted(&legacy));
+        assert_eq!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, OWNER, &legacy).unwrap(), SECRET);
+        assert!(needs_reencryption(&keyring, &legacy));
+
+        let rewrapped = rewrap_secret(&keyring, JWT_SECRET_CONTEXT, OWNER, &legacy).unwrap();
+        assert!(rewrapped.starts_with(ENCRYPTED_PREFIX));
+        assert!(!needs_reencryption(&keyring, &rewrapped));
+        assert_eq!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, OWNER, &rewrapped).unwrap(), SECRET);
+        assert!(decrypt_secret(Some(&keyring), JWT_SECRET_CONTEXT, "mallory", &rewrapped).is_err());
+    }
+}
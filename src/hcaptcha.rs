@@ -0,0 +1,49 @@
+use serde::Serialize;
+use crate::human_verifier::{HumanVerifier, VerificationError, VerifiedChallenge, VerifyOptions, post_siteverify, check_siteverify_response};
+
+const SITEVERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+
+#[derive(Serialize)]
+struct HCaptchaVerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sitekey: Option<&'a str>, // Makes hCaptcha reject tokens solved for another of our sitekeys
+}
+
+pub struct HCaptchaVerifier {
+    secret: String,
+    site_key: Option<String>,
+    verify_url: String,
+}
+
+impl HCaptchaVerifier {
+    pub fn new(secret: String, site_key: Option<String>, verify_url: Option<String>) -> Self {
+        HCaptchaVerifier {
+            secret,
+            site_key,
+            verify_url: verify_url.unwrap_or_else(|| SITEVERIFY_URL.to_string()),
+        }
+    }
+}
+
+impl HumanVerifier for HCaptchaVerifier {
+    async fn verify(&self, token: &str, options: &VerifyOptions) -> std::result::Result<VerifiedChallenge, VerificationError> {
+        let body = serde_urlencoded::to_string(HCaptchaVerifyRequest {
+            secret: &self.secret,
+            response: token,
+            remoteip: options.remote_ip.as_deref(),
+            sitekey: self.site_key.as_deref(),
+        })
+        .map_err(|err| VerificationError::Unavailable(err.to_string()))?;
+
+        let response = post_siteverify(&self.verify_url, &body)
+            .await
+            .map_err(|err| VerificationError::Unavailable(err.to_string()))?;
+
+        // hCaptcha has no actions, the sitekey check above is the closest equivalent
+        check_siteverify_response(&response, options, false)
+    }
+}
@@ -0,0 +1,461 @@
+use std::fmt;
+use serde::Deserialize;
+use worker::*;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use crate::token_lookup::to_hex;
+use crate::turnstile::TurnstileVerifier;
+use crate::hcaptcha::HCaptchaVerifier;
+use crate::recaptcha::RecaptchaVerifier;
+use crate::config::{env_list, env_parse, env_string, MIN_KV_TTL_SECONDS};
+
+// Bot protection for /login and /register. CAPTCHA_PROVIDER selects the implementation:
+//
+//     turnstile (default)  Cloudflare Turnstile, secret TURNSTILE_SECRET_KEY
+//     hcaptcha             hCaptcha, secret HCAPTCHA_SECRET_KEY
+//     recaptcha            reCAPTCHA v3, secret RECAPTCHA_SECRET_KEY, RECAPTCHA_MIN_SCORE
+//     stub                 no network, accepts CAPTCHA_STUB_TOKEN (for local and integration tests,
+//                          refused unless ALLOW_CAPTCHA_STUB = "true")
+//
+// CAPTCHA_VERIFY_URL replaces the provider's siteverify URL, e.g. with a local stub server.
+
+// Turnstile tokens are valid for 300 seconds, accept nothing older by default
+const DEFAULT_MAX_AGE_SECONDS: i64 = 300;
+
+// Error codes meaning our secret is wrong, not the visitor's token (same names for all providers)
+const SECRET_ERROR_CODES: [&str; 2] = ["missing-input-secret", "invalid-input-secret"];
+
+// KV key prefix for hashes of tokens that were already presented
+const REDEEMED_TOKEN_PREFIX: &str = "captcha_redeemed:";
+
+#[derive(Debug)]
+pub enum VerificationError {
+    Rejected(Vec<String>),            // siteverify said no, with its error codes
+    InvalidSecret(Vec<String>),       // Our secret key was refused
+    HostnameMismatch(Option<String>), // Solved on a site outside TURNSTILE_ALLOWED_HOSTNAMES
+    ActionMismatch { expected: String, actual: Option<String> },
+    LowScore(f64),                    // reCAPTCHA v3 score under RECAPTCHA_MIN_SCORE
+    Stale(i64),                       // challenge_ts older than the limit, in seconds
+    Unavailable(String),              // siteverify could not be reached or answered garbage
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerificationError::Rejected(codes) => write!(f, "Verification failed: {}", codes.join(", ")),
+            VerificationError::InvalidSecret(codes) => write!(f, "Verification secret was rejected: {}", codes.join(", ")),
+            VerificationError::HostnameMismatch(hostname) => {
+                write!(f, "Challenge hostname {} is not allowed", hostname.as_deref().unwrap_or("<none>"))
+            }
+            VerificationError::ActionMismatch { expected, actual } => {
+                write!(f, "Challenge action {} does not match {}", actual.as_deref().unwrap_or("<none>"), expected)
+            }
+            VerificationError::LowScore(score) => write!(f, "Verification score {} is below the threshold", score),
+            VerificationError::Stale(age) => write!(f, "Challenge is {} seconds old", age),
+            VerificationError::Unavailable(err) => write!(f, "Verification service unavailable: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+// What a token has to satisfy besides being valid
+pub struct VerifyOptions {
+    pub remote_ip: Option<String>,
+    pub expected_action: String,
+    pub allowed_hostnames: Vec<String>, // Empty means any hostname
+    pub max_age_seconds: i64,
+}
+
+impl VerifyOptions {
+    // `action` is the endpoint the widget was rendered for ("login", "register")
+    pub fn from_request(req: &Request, env: &Env, action: &str) -> Self {
+        let allowed_hostnames = env_list(env, "TURNSTILE_ALLOWED_HOSTNAMES")
+            .iter()
+            .map(|hostname| hostname.to_ascii_lowercase())
+            .collect();
+
+        VerifyOptions {
+            remote_ip: req.headers().get("CF-Connecting-IP").ok().flatten(),
+            expected_action: action.to_string(),
+            allowed_hostnames,
+            max_age_seconds: env_parse(env, "TURNSTILE_MAX_AGE_SECONDS", DEFAULT_MAX_AGE_SECONDS),
+        }
+    }
+}
+
+// The siteverify answer, a superset of what Turnstile, hCaptcha and reCAPTCHA return
+#[derive(Deserialize)]
+pub struct SiteverifyResponse {
+    pub success: bool,
+    #[serde(rename = "error-codes", default)]
+    pub error_codes: Vec<String>,
+    pub challenge_ts: Option<String>,
+    pub hostname: Option<String>,
+    pub action: Option<String>,
+    pub score: Option<f64>,
+}
+
+// What siteverify vouched for once a token passed, the stub leaves both empty
+#[derive(Debug, Default)]
+pub struct VerifiedChallenge {
+    pub challenge_ts: String,
+    pub hostname: String,
+}
+
+// POST a form encoded body to a siteverify endpoint
+pub async fn post_siteverify(url: &str, body: &str) -> Result<SiteverifyResponse> {
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.body = Some(body.into());
+
+    // Set content type header for form data
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+    init.headers = headers;
+
+    let request = Request::new_with_init(url, &init)?;
+    let mut response = Fetch::Request(request).send().await?;
+    response.json().await
+}
+
+// Checks shared by every provider. `check_action` is false for providers without actions (hCaptcha).
+pub fn check_siteverify_response(
+    response: &SiteverifyResponse,
+    options: &VerifyOptions,
+    check_action: bool,
+) -> std::result::Result<VerifiedChallenge, VerificationError> {
+    if !response.success {
+        let codes = response.error_codes.clone();
+        if codes.iter().any(|code| SECRET_ERROR_CODES.contains(&code.as_str())) {
+            return Err(VerificationError::InvalidSecret(codes));
+        }
+        return Err(VerificationError::Rejected(codes));
+    }
+
+    // A token solved on another site that uses our sitekey must not count
+    if !options.allowed_hostnames.is_empty() {
+        let hostname_allowed = response
+            .hostname
+            .as_deref()
+            .is_some_and(|hostname| options.allowed_hostnames.contains(&hostname.to_ascii_lowercase()));
+        if !hostname_allowed {
+            return Err(VerificationError::HostnameMismatch(response.hostname.clone()));
+        }
+    }
+
+    // A token solved for registration must not be replayed against login and vice versa
+    if check_action && response.action.as_deref() != Some(options.expected_action.as_str()) {
+        return Err(VerificationError::ActionMismatch {
+            expected: options.expected_action.clone(),
+            actual: response.action.clone(),
+        });
+    }
+
+    let challenge_ts = response.challenge_ts.as_deref().unwrap_or_default();
+    let solved_at = DateTime::parse_from_rfc3339(challenge_ts)
+        .map_err(|_| VerificationError::Rejected(vec!["missing-challenge-ts".to_string()]))?;
+    let age = Utc::now().timestamp() - solved_at.timestamp();
+    if age > options.max_age_seconds {
+        return Err(VerificationError::Stale(age));
+    }
+
+    Ok(VerifiedChallenge {
+        challenge_ts: challenge_ts.to_string(),
+        hostname: response.hostname.clone().unwrap_or_default().to_ascii_lowercase(),
+    })
+}
+
+// Record a verified token as redeemed, returning false if it was already seen. This runs after
+// siteverify so that tokens which fail verification never take up KV writes, and the record is
+// keyed on what siteverify vouched for (challenge_ts and hostname) next to the token itself. It
+// only has to be remembered until the token is too old to pass the challenge_ts check anyway. KV
+// is eventually consistent, so two requests racing through different locations can both get
+// through; siteverify's own single-use check still stops the second one there.
+pub async fn redeem_token(env: &Env, token: &str, challenge: &VerifiedChallenge, options: &VerifyOptions) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    let key = format!("{}{}", REDEEMED_TOKEN_PREFIX, redemption_id(token, challenge));
+
+    if kv.get(&key).text().await?.is_some() {
+        return Ok(false);
+    }
+
+    let ttl = (options.max_age_seconds.max(0) as u64).max(MIN_KV_TTL_SECONDS);
+    kv.put(&key, "1")?.expiration_ttl(ttl).execute().await?;
+    Ok(true)
+}
+
+// Length-prefixed so that no two (challenge_ts, hostname, token) triples hash the same input
+fn redemption_id(token: &str, challenge: &VerifiedChallenge) -> String {
+    let mut hasher = Sha256::new();
+    for part in [challenge.challenge_ts.as_str(), challenge.hostname.as_str(), token] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    to_hex(&hasher.finalize())
+}
+
+// Anything that can tell a human from a bot given a client token
+pub trait HumanVerifier {
+    async fn verify(&self, token: &str, options: &VerifyOptions) -> std::result::Result<VerifiedChallenge, VerificationError>;
+}
+
+// Test double, never talks to the network. CAPTCHA_STUB_TOKEN passes, alone or followed by
+// ":<anything>" so that tests can send a fresh token per request past the replay check.
+pub struct StubVerifier {
+    pass_token: String,
+}
+
+impl StubVerifier {
+    // Anyone knowing the token gets past the CAPTCHA, so a deployment has to opt in explicitly
+    // and pick the token itself. The error names the missing variable.
+    fn new(allowed: Option<String>, pass_token: Option<String>) -> std::result::Result<Self, String> {
+        if allowed.as_deref() != Some("true") {
+            return Err("ALLOW_CAPTCHA_STUB".to_string());
+        }
+        match pass_token.filter(|token| !token.is_empty()) {
+            Some(pass_token) => Ok(StubVerifier { pass_token }),
+            None => Err("CAPTCHA_STUB_TOKEN".to_string()),
+        }
+    }
+}
+
+impl HumanVerifier for StubVerifier {
+    async fn verify(&self, token: &str, _options: &VerifyOptions) -> std::result::Result<VerifiedChallenge, VerificationError> {
+        let passes = token
+            .strip_prefix(self.pass_token.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(':'));
+        if passes {
+            Ok(VerifiedChallenge::default())
+        } else {
+            Err(VerificationError::Rejected(vec!["invalid-input-response".to_string()]))
+        }
+    }
+}
+
+// The configured provider
+pub enum CaptchaVerifier {
+    Turnstile(TurnstileVerifier),
+    HCaptcha(HCaptchaVerifier),
+    Recaptcha(RecaptchaVerifier),
+    Stub(StubVerifier),
+}
+
+fn secret(env: &Env, name: &str) -> std::result::Result<String, String> {
+    env.secret(name)
+        .map(|secret| secret.to_string())
+        .map_err(|_| name.to_string())
+}
+
+// The CAPTCHA_PROVIDER in effect, as a metrics label
+pub fn provider_name(env: &Env) -> &'static str {
+    match env.var("CAPTCHA_PROVIDER").map(|v| v.to_string()).unwrap_or_default().as_str() {
+        "hcaptcha" => "hcaptcha",
+        "recaptcha" => "recaptcha",
+        "stub" => "stub",
+        _ => "turnstile",
+    }
+}
+
+impl CaptchaVerifier {
+    pub fn is_stub(&self) -> bool {
+        matches!(self, CaptchaVerifier::Stub(_))
+    }
+
+    // Build the provider named by CAPTCHA_PROVIDER, the error names the missing secret
+    pub fn from_env(env: &Env) -> std::result::Result<Self, String> {
+        let provider = env.var("CAPTCHA_PROVIDER").map(|v| v.to_string()).unwrap_or_default();
+        let verify_url = env.var("CAPTCHA_VERIFY_URL").map(|v| v.to_string()).ok();
+
+        match provider.as_str() {
+            "hcaptcha" => Ok(CaptchaVerifier::HCaptcha(HCaptchaVerifier::new(
+                secret(env, "HCAPTCHA_SECRET_KEY")?,
+                env.var("HCAPTCHA_SITE_KEY").map(|v| v.to_string()).ok(),
+                verify_url,
+            ))),
+            "recaptcha" => Ok(CaptchaVerifier::Recaptcha(RecaptchaVerifier::new(
+                secret(env, "RECAPTCHA_SECRET_KEY")?,
+                env_string(env, "RECAPTCHA_MIN_SCORE").and_then(|score| score.parse().ok()),
+                verify_url,
+            ))),
+            "stub" => Ok(CaptchaVerifier::Stub(StubVerifier::new(
+                env.var("ALLOW_CAPTCHA_STUB").map(|v| v.to_string()).ok(),
+                env.var("CAPTCHA_STUB_TOKEN").map(|v| v.to_string()).ok(),
+            )?)),
+            _ => Ok(CaptchaVerifier::Turnstile(TurnstileVerifier::new(
+                secret(env, "TURNSTILE_SECRET_KEY")?,
+                verify_url,
+            ))),
+        }
+    }
+}
+
+impl HumanVerifier for CaptchaVerifier {
+    async fn verify(&self, token: &str, options: &VerifyOptions) -> std::result::Result<VerifiedChallenge, VerificationError> {
+        match self {
+            CaptchaVerifier::Turnstile(verifier) => verifier.verify(token, options).await,
+            CaptchaVerifier::HCaptcha(verifier) => verifier.verify(token, options).await,
+            CaptchaVerifier::Recaptcha(verifier) => verifier.verify(token, options).await,
+            CaptchaVerifier::Stub(verifier) => verifier.verify(token, options).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    // The stub never waits on anything, one poll finishes it
+    fn run<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("stub verification did not complete"),
+        }
+    }
+
+    fn options() -> VerifyOptions {
+        VerifyOptions {
+            remote_ip: None,
+            expected_action: "login".to_string(),
+            allowed_hostnames: Vec::new(),
+            max_age_seconds: DEFAULT_MAX_AGE_SECONDS,
+        }
+    }
+
+    fn stub(pass_token: &str) -> CaptchaVerifier {
+        CaptchaVerifier::Stub(StubVerifier::new(Some("true".to_string()), Some(pass_token.to_string())).unwrap())
+    }
+
+    #[test]
+    fn stub_is_refused_without_the_opt_in_flag() {
+        let token = Some("secret-pass".to_string());
+        assert_eq!(StubVerifier::new(None, token.clone()).err().as_deref(), Some("ALLOW_CAPTCHA_STUB"));
+        assert_eq!(StubVerifier::new(Some("1".to_string()), token).err().as_deref(), Some("ALLOW_CAPTCHA_STUB"));
+    }
+
+    #[test]
+    fn stub_has_no_default_token() {
+        let allowed = Some("true".to_string());
+        assert_eq!(StubVerifier::new(allowed.clone(), None).err().as_deref(), Some("CAPTCHA_STUB_TOKEN"));
+        assert_eq!(StubVerifier::new(allowed, Some(String::new())).err().as_deref(), Some("CAPTCHA_STUB_TOKEN"));
+    }
+
+    #[test]
+    fn stub_accepts_its_token_with_an_optional_nonce() {
+        let verifier = stub("secret-pass");
+        assert!(run(verifier.verify("secret-pass", &options())).is_ok());
+        assert!(run(verifier.verify("secret-pass:4f1c", &options())).is_ok());
+    }
+
+    #[test]
+    fn stub_rejects_other_tokens() {
+        let verifier = stub("secret-pass");
+        for token in ["", "test-pass", "secret", "secret-passX", "xsecret-pass"] {
+            match run(verifier.verify(token, &options())) {
+                Err(VerificationError::Rejected(codes)) => assert_eq!(codes, ["invalid-input-response"]),
+                other => panic!("{:?} was not rejected: {:?}", token, other),
+            }
+        }
+    }
+
+    #[test]
+    fn redemption_id_covers_the_verified_challenge() {
+        let challenge = |ts: &str, hostname: &str| VerifiedChallenge { challenge_ts: ts.to_string(), hostname: hostname.to_string() };
+        let id = redemption_id("token", &challenge("2026-01-01T00:00:00Z", "app.example.com"));
+        assert_eq!(id, redemption_id("token", &challenge("2026-01-01T00:00:00Z", "app.example.com")));
+        assert_ne!(id, redemption_id("token", &challenge("2026-01-01T00:00:01Z", "app.example.com")));
+        assert_ne!(id, redemption_id("token", &challenge("2026-01-01T00:00:00Z", "other.example.com")));
+        assert_ne!(id, redemption_id("other", &challenge("2026-01-01T00:00:00Z", "app.example.com")));
+        assert_ne!(redemption_id("c", &challenge("a", "b")), redemption_id("", &challenge("a", "bc")));
+    }
+
+    fn siteverify(hostname: &str, action: &str, age_seconds: i64) -> SiteverifyResponse {
+        let challenge_ts = Utc::now() - chrono::Duration::seconds(age_seconds);
+        SiteverifyResponse {
+            success: true,
+            error_codes: Vec::new(),
+            challenge_ts: Some(challenge_ts.to_rfc3339()),
+            hostname: Some(hostname.to_string()),
+            action: Some(action.to_string()),
+            score: None,
+        }
+    }
+
+    fn restricted_options() -> VerifyOptions {
+        VerifyOptions { allowed_hostnames: vec!["app.example.com".to_string()], ..options() }
+    }
+
+    #[test]
+    fn fresh_response_for_allowed_hostname_and_action_passes() {
+        let response = siteverify("App.Example.com", "login", 10);
+        assert!(check_siteverify_response(&response, &restricted_options(), true).is_ok());
+    }
+
+    #[test]
+    fn response_from_another_hostname_is_refused() {
+        let response = siteverify("evil.example.net", "login", 10);
+        match check_siteverify_response(&response, &restricted_options(), true) {
+            Err(VerificationError::HostnameMismatch(hostname)) => assert_eq!(hostname.as_deref(), Some("evil.example.net")),
+            other => panic!("hostname was not checked: {:?}", other),
+        }
+
+        let response = SiteverifyResponse { hostname: None, ..siteverify("", "login", 10) };
+        assert!(matches!(
+            check_siteverify_response(&response, &restricted_options(), true),
+            Err(VerificationError::HostnameMismatch(None))
+        ));
+    }
+
+    #[test]
+    fn response_for_another_action_is_refused_unless_actions_are_unsupported() {
+        let response = siteverify("app.example.com", "register", 10);
+        match check_siteverify_response(&response, &restricted_options(), true) {
+            Err(VerificationError::ActionMismatch { expected, actual }) => {
+                assert_eq!(expected, "login");
+                assert_eq!(actual.as_deref(), Some("register"));
+            }
+            other => panic!("action was not checked: {:?}", other),
+        }
+        assert!(check_siteverify_response(&response, &restricted_options(), false).is_ok());
+    }
+
+    #[test]
+    fn response_without_a_challenge_timestamp_is_refused() {
+        for challenge_ts in [None, Some("yesterday".to_string())] {
+            let response = SiteverifyResponse { challenge_ts, ..siteverify("app.example.com", "login", 0) };
+            match check_siteverify_response(&response, &restricted_options(), true) {
+                Err(VerificationError::Rejected(codes)) => assert_eq!(codes, ["missing-challenge-ts"]),
+                other => panic!("timestamp was not required: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn stale_response_is_refused() {
+        let response = siteverify("app.example.com", "login", DEFAULT_MAX_AGE_SECONDS + 60);
+        match check_siteverify_response(&response, &restricted_options(), true) {
+            Err(VerificationError::Stale(age)) => assert!(age > DEFAULT_MAX_AGE_SECONDS),
+            other => panic!("age was not checked: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsuccessful_response_distinguishes_our_secret_from_the_token() {
+        let failed = |code: &str| SiteverifyResponse {
+            success: false,
+            error_codes: vec![code.to_string()],
+            ..siteverify("app.example.com", "login", 0)
+        };
+        assert!(matches!(
+            check_siteverify_response(&failed("invalid-input-secret"), &options(), true),
+            Err(VerificationError::InvalidSecret(_))
+        ));
+        assert!(matches!(
+            check_siteverify_response(&failed("timeout-or-duplicate"), &options(), true),
+            Err(VerificationError::Rejected(_))
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+use worker::*;
+
+// Time-ordered indexes in KV, for jobs that act on records once a point in time has passed. Each
+// entry is an empty value under "<prefix><time>:<id>", the time zero padded so that KV's
+// lexicographic listing order is chronological. A job lists only the entries that are due
+// instead of every record, and removes each entry once it has handled it.
+
+pub struct TimeIndex {
+    prefix: &'static str,
+}
+
+impl TimeIndex {
+    pub const fn new(prefix: &'static str) -> Self {
+        TimeIndex { prefix }
+    }
+
+    pub fn key(&self, at: i64, id: &str) -> String {
+        format!("{}{:012}:{}", self.prefix, at.max(0), id)
+    }
+
+    fn parse_key(&self, key: &str) -> Option<(String, i64)> {
+        let (at, id) = key.strip_prefix(self.prefix)?.split_once(':')?;
+        Some((id.to_string(), at.parse().ok()?))
+    }
+
+    // (id, time) of the listed keys up to and including `until`, in listing order
+    fn due_entries<'a>(&self, keys: impl Iterator<Item = &'a str>, until: i64) -> Vec<(String, i64)> {
+        keys.filter_map(|key| self.parse_key(key))
+            .take_while(|(_, at)| *at <= until)
+            .collect()
+    }
+
+    pub async fn add(&self, env: &Env, at: i64, id: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let kv = env.kv("USERS_KV")?;
+        kv.put(&self.key(at, id), "")?.execute().await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self, env: &Env, at: i64, id: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let kv = env.kv("USERS_KV")?;
+        kv.delete(&self.key(at, id)).await?;
+        Ok(())
+    }
+
+    // Up to `limit` (id, time) entries at or before `until`, oldest first
+    pub async fn due(&self, env: &Env, until: i64, limit: u64) -> std::result::Result<Vec<(String, i64)>, Box<dyn std::error::Error>> {
+        let kv = env.kv("USERS_KV")?;
+        let page = kv.list().prefix(self.prefix.to_string()).limit(limit).execute().await?;
+        Ok(self.due_entries(page.keys.iter().map(|key| key.name.as_str()), until))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: TimeIndex = TimeIndex::new("test_due:");
+
+    #[test]
+    fn keys_sort_by_time() {
+        let earlier = INDEX.key(999_999_999, "zoe");
+        let later = INDEX.key(1_000_000_000, "adam");
+
+        assert!(earlier < later);
+        assert_eq!(later, "test_due:001000000000:adam");
+        assert_eq!(INDEX.key(-5, "adam"), "test_due:000000000000:adam");
+    }
+
+    #[test]
+    fn keys_parse_back() {
+        assert_eq!(INDEX.parse_key(&INDEX.key(1234, "a:b")), Some(("a:b".to_string(), 1234)));
+        assert_eq!(INDEX.parse_key("other:000000001234:adam"), None);
+        assert_eq!(INDEX.parse_key("test_due:soon:adam"), None);
+        assert_eq!(INDEX.parse_key("test_due:000000001234"), None);
+    }
+
+    #[test]
+    fn only_entries_up_to_the_cutoff_are_due() {
+        let keys = [
+            INDEX.key(100, "first"),
+            "test_due:garbage".to_string(),
+            INDEX.key(200, "second"),
+            INDEX.key(201, "later"),
+            INDEX.key(150, "unsorted"),
+        ];
+        let due = INDEX.due_entries(keys.iter().map(String::as_str), 200);
+
+        assert_eq!(due, [("first".to_string(), 100), ("second".to_string(), 200)]);
+        assert!(INDEX.due_entries(keys.iter().map(String::as_str), 99).is_empty());
+    }
+}
@@ -1,23 +1,55 @@
 use worker::*;
+use chrono::Utc;
 use crate::auth::UserData;
+use crate::encryption::{MasterKeyring, encrypt_secret, decrypt_secret, needs_reencryption, rewrap_secret, JWT_SECRET_CONTEXT};
+use crate::metrics::Metrics;
+
+// Serialize a user for KV, encrypting its JWT secret when a master key is configured
+fn seal_user_data(env: &Env, user_data: &UserData) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    match MasterKeyring::from_env(env)? {
+        Some(keyring) => {
+            let mut value = serde_json::to_value(user_data)?;
+            value["jwt_secret"] = encrypt_secret(&keyring, JWT_SECRET_CONTEXT, user_data.id(), &user_data.jwt_secret)?.into();
+            Ok(value.to_string())
+        }
+        None => Ok(serde_json::to_string(user_data)?),
+    }
+}
+
+// Deserialize a user from KV, decrypting its JWT secret
+fn open_user_data(env: &Env, user_data_json: &str) -> std::result::Result<UserData, Box<dyn std::error::Error>> {
+    let mut user_data: UserData = serde_json::from_str(user_data_json)?;
+    let keyring = MasterKeyring::from_env(env)?;
+    user_data.jwt_secret = decrypt_secret(keyring.as_ref(), JWT_SECRET_CONTEXT, user_data.id(), &user_data.jwt_secret)?;
+    Ok(user_data)
+}
 
 pub async fn get_user_from_kv(env: &Env, username: &str) -> std::result::Result<UserData, Box<dyn std::error::Error>> {
+    find_user_in_kv(env, username).await?.ok_or_else(|| "User not found".into())
+}
+
+// Like get_user_from_kv, but tells a missing user (None) apart from a failed read (Err)
+pub async fn find_user_in_kv(env: &Env, username: &str) -> std::result::Result<Option<UserData>, Box<dyn std::error::Error>> {
     let kv = env.kv("USERS_KV")?;
     
-    match kv.get(username).text().await? {
-        Some(user_data_json) => {
-            let user_data: UserData = serde_json::from_str(&user_data_json)?;
-            Ok(user_data)
-        }
-        None => Err("User not found".into())
+    let started = Utc::now().timestamp_millis();
+    let user_data_json = kv.get(username).text().await;
+    Metrics::from_env(env).kv("get_user", user_data_json.is_ok(), Utc::now().timestamp_millis() - started);
+
+    match user_data_json? {
+        Some(user_data_json) => Ok(Some(open_user_data(env, &user_data_json)?)),
+        None => Ok(None)
     }
 }
 
 pub async fn store_user_in_kv(env: &Env, username: &str, user_data: &UserData) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let kv = env.kv("USERS_KV")?;
-    let user_data_json = serde_json::to_string(user_data)?;
+    let user_data_json = seal_user_data(env, user_data)?;
     
-    kv.put(username, user_data_json)?.execute().await?;
+    let started = Utc::now().timestamp_millis();
+    let stored = kv.put(username, user_data_json)?.execute().await;
+    Metrics::from_env(env).kv("put_user", stored.is_ok(), Utc::now().timestamp_millis() - started);
+    stored?;
     Ok(())
 }
 
@@ -55,18 +87,18 @@ pub async fn update_user_in_kv(
                             // Delete old username entry
                             kv.delete(old_username).await?;
                             // Store with new username
-                            let user_data_json = serde_json::to_string(user_data)?;
+                            let user_data_json = seal_user_data(env, user_data)?;
                             kv.put(new_user, user_data_json)?.execute().await?;
                         }
                     }
                 } else {
                     // Username not changing, just update the data
-                    let user_data_json = serde_json::to_string(user_data)?;
+                    let user_data_json = seal_user_data(env, user_data)?;
                     kv.put(old_username, user_data_json)?.execute().await?;
                 }
             } else {
                 // Username not changing, just update the data
-                let user_data_json = serde_json::to_string(user_data)?;
+                let user_data_json = seal_user_data(env, user_data)?;
                 kv.put(old_username, user_data_json)?.execute().await?;
             }
             Ok(())
@@ -74,3 +106,47 @@ pub async fn update_user_in_kv(
         None => Err("User not found".into())
     }
 }
+
+// Re-encrypt one page of user records under the current master key.
+// Returns (records scanned, records updated, cursor for the next page).
+pub async fn reencrypt_users_page(
+    env: &Env,
+    keyring: &MasterKeyring,
+    cursor: Option<String>,
+    limit: u64,
+) -> std::result::Result<(usize, usize, Option<String>), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    let mut list = kv.list().limit(limit);
+    if let Some(cursor) = cursor {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+
+    let mut updated = 0;
+    for key in &page.keys {
+        let Some(record_json) = kv.get(&key.name).text().await? else {
+            continue;
+        };
+
+        // The namespace also holds device grants and token lookups, only user records are touched
+        let Ok(mut record) = serde_json::from_str::<serde_json::Value>(&record_json) else {
+            continue;
+        };
+        let Ok(user_data) = serde_json::from_value::<UserData>(record.clone()) else {
+            continue;
+        };
+        let Some(stored_secret) = record["jwt_secret"].as_str() else {
+            continue;
+        };
+
+        if needs_reencryption(keyring, stored_secret) {
+            record["jwt_secret"] = rewrap_secret(keyring, JWT_SECRET_CONTEXT, user_data.id(), stored_secret)?.into();
+            kv.put(&key.name, record.to_string())?.execute().await?;
+            updated += 1;
+        }
+    }
+
+    let next_cursor = if page.list_complete { None } else { page.cursor };
+    Ok((page.keys.len(), updated, next_cursor))
+}
@@ -3,18 +3,88 @@ use argon2::{
     Argon2,
 };
 use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, decode, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{encode, decode, decode_header, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use worker::*;
 use rand_core::RngCore;
 use base64::{Engine as _, engine::general_purpose};
 
+mod human_verifier;
 mod turnstile;
+mod hcaptcha;
+mod recaptcha;
+mod risk;
+mod client;
 mod auth;
 mod kv_store;
+mod device;
+mod scopes;
+mod access_tokens;
+mod token_lookup;
+mod encryption;
+mod signing_keys;
+mod cors;
+mod session;
+mod audit;
+mod login_history;
+mod webhooks;
+mod tasks;
+mod maintenance;
+mod account_deletion;
+mod data_export;
+mod logging;
+mod metrics;
+mod config;
+mod kv_index;
 
-use turnstile::verify_turnstile_token;
-use auth::{LoginRequest, LoginResponse, Claims, UserData, DeleteResponse, UpdateUserRequest, UpdateUserResponse};
-use kv_store::{get_user_from_kv, store_user_in_kv, delete_user_from_kv, update_user_in_kv};
+use human_verifier::{CaptchaVerifier, HumanVerifier, VerificationError, VerifyOptions, provider_name, redeem_token};
+use auth::{
+    LoginRequest, LoginResponse, Claims, UserData, DeleteResponse, DeleteUserRequest, UpdateUserRequest, UpdateUserResponse,
+    AuthContext, AuthMethod, UserProfile, ProfileResponse, RestoreAccountRequest,
+};
+use kv_store::{get_user_from_kv, store_user_in_kv, update_user_in_kv, reencrypt_users_page};
+use device::{
+    DeviceGrant, DeviceGrantStatus, DeviceCodeRequest, DeviceCodeResponse, DeviceVerifyRequest, TokenRequest, TokenResponse,
+    DEVICE_CODE_GRANT_TYPE, generate_device_code, generate_user_code, normalize_user_code, oauth_error_response,
+    store_device_grant, get_device_grant, get_device_code_for_user_code, delete_device_grant,
+};
+use scopes::{has_scopes, resolve_scopes, PROFILE_READ, PROFILE_WRITE, ACCOUNT_DELETE, DEFAULT_SCOPES};
+use access_tokens::{
+    PersonalAccessToken, AccessTokenSummary, CreateAccessTokenRequest, CreateAccessTokenResponse, ListAccessTokensResponse,
+    MAX_ACCESS_TOKENS_PER_USER, generate_access_token, hash_access_token, verify_access_token, is_access_token,
+    parse_access_token_id, store_access_token_owner, get_access_token_owner, delete_access_token_entries,
+    add_to_expiry_index,
+};
+use token_lookup::{lookup_tag, verify_lookup_tag, JWT_LOOKUP_CONTEXT};
+use encryption::{MasterKeyring, ReencryptRequest};
+use signing_keys::{RotationReport, get_signing_keys, store_signing_keys, signing_key_der, reencrypt_signing_keys, find_verification_key};
+use cors::CorsPolicy;
+use risk::{
+    RiskConfig, assess as assess_risk, record_login_failure, record_login_success, record_registration,
+    generate_device_token, device_cookie,
+};
+use session::{
+    SESSION_COOKIE, CSRF_HEADER, session_cookies_enabled, generate_csrf_token, get_cookie, set_session_cookies,
+    clear_session_cookies, requires_csrf_check, verify_csrf_token,
+};
+use audit::{AuditEventType, AuditOutcome, AuditRecorder, AuditSink, DEFAULT_QUERY_LIMIT};
+use login_history::{
+    LoginRecord, LoginHistoryResponse, get_login_history, record_login_attempt, rename_login_history,
+};
+use webhooks::{
+    WebhookConfig, WebhookEvent, USER_CREATED, USER_USERNAME_CHANGED, USER_PASSWORD_CHANGED, USER_DELETION_SCHEDULED,
+    USER_RESTORED, USER_DELETED,
+};
+use tasks::{Task, TaskMessage, TaskQueue, WaitUntil, process as process_task, drain_memory_queue, run_inline};
+use maintenance::{MaintenanceJob, JobReport, JobStatus, jobs_for_cron, prune_expired_access_tokens, purge_deleted_accounts};
+use account_deletion::{
+    grace_period_seconds, schedule_deletion, generate_restore_token, restore_token_username, verify_restore_token, purge_user,
+    add_to_purge_index, remove_from_purge_index,
+};
+use data_export::{CURRENT_PASSWORD_HEADER, build_export, content_disposition};
+use logging::REQUEST_ID_HEADER;
+use metrics::{Metrics, route_template};
+use config::env_parse;
+use subtle::ConstantTimeEq;
 
 // Generate a secure 512-bit (64 bytes) JWT secret key
 fn generate_jwt_secret() -> String {
@@ -23,27 +93,69 @@ fn generate_jwt_secret() -> String {
     general_purpose::STANDARD.encode(secret)
 }
 
-// Generate JWT token using user's unique secret
-fn generate_jwt_token(user_data: &UserData, jwt_expiration_minutes: i64) -> std::result::Result<String, Box<dyn std::error::Error>> {
+// Random 128-bit id that stays with an account across renames
+fn generate_account_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    token_lookup::to_hex(&id)
+}
+
+// Claims for a new access token
+fn build_claims(user_data: &UserData, jwt_expiration_minutes: i64, scope: &str, auth_time: usize, csrf: &str) -> Claims {
     let now = Utc::now();
     let expiration = now + Duration::minutes(jwt_expiration_minutes);
-      let claims = Claims {
+    Claims {
         sub: user_data.username.clone(),
         exp: expiration.timestamp() as usize,
         iat: now.timestamp() as usize,
         ver: user_data.jwt_version,
+        scope: scope.to_string(),
+        auth_time,
+        csrf: csrf.to_string(),
+    }
+}
+
+// Generate JWT token using user's unique secret, tagging the header so the user lookup is authenticated
+fn generate_jwt_token(
+    user_data: &UserData,
+    claims: &Claims,
+    lookup_secret: &[u8],
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let header = Header {
+        kid: Some(lookup_tag(lookup_secret, JWT_LOOKUP_CONTEXT, &user_data.username)),
+        ..Header::default()
     };
 
     let secret_bytes = general_purpose::STANDARD.decode(&user_data.jwt_secret)?;
     let token = encode(
-        &Header::default(),
-        &claims,
+        &header,
+        claims,
         &EncodingKey::from_secret(&secret_bytes),
     )?;
 
     Ok(token)
 }
 
+// Generate JWT token signed by the current server-managed Ed25519 key, identified by its kid
+fn generate_server_jwt_token(
+    claims: &Claims,
+    kid: &str,
+    private_key_der: &[u8],
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let header = Header {
+        kid: Some(kid.to_string()),
+        ..Header::new(Algorithm::EdDSA)
+    };
+
+    let token = encode(
+        &header,
+        claims,
+        &EncodingKey::from_ed_der(private_key_der),
+    )?;
+
+    Ok(token)
+}
+
 // Verify JWT token using user's unique secret
 async fn verify_jwt_token_with_user_secret(
     token: &str, 
@@ -65,147 +177,857 @@ async fn verify_jwt_token_with_user_secret(
     Ok(token_data.claims)
 }
 
+// Usernames are stored as raw KV keys, internal records all use a ':' separated prefix
+fn validate_username(username: &str) -> std::result::Result<(), Error> {
+    if username.is_empty() || username.contains(':') {
+        return Err(Error::InvalidUsername);
+    }
+    Ok(())
+}
+
+// Verify JWT token signed by a server-managed key (current or retired)
+fn verify_jwt_token_with_server_key(
+    token: &str,
+    public_key: &str,
+) -> std::result::Result<Claims, Box<dyn std::error::Error>> {
+    let token_data = decode::<Claims>(
+        token,
+        &DecodingKey::from_ed_components(public_key)?,
+        &Validation::new(Algorithm::EdDSA),
+    )?;
+
+    Ok(token_data.claims)
+}
+
+// JWT_SIGNING_MODE = "server" signs with rotating server-managed keys, anything else keeps
+// the per-user secrets. Verification accepts both, so switching modes does not log anyone out.
+fn server_signing_enabled(env: &Env) -> bool {
+    env.var("JWT_SIGNING_MODE")
+        .map(|mode| mode.to_string() == "server")
+        .unwrap_or(false)
+}
+
+// Issue an access token for the user, returning it with its lifetime in seconds.
+// `csrf` is only set for cookie sessions.
+async fn issue_jwt_token(env: &Env, user_data: &UserData, scope: &str, auth_time: i64, csrf: &str) -> std::result::Result<(String, i64), Error> {
+    let expiration_minutes = jwt_expiration_minutes(env);
+    let claims = build_claims(user_data, expiration_minutes, scope, auth_time as usize, csrf);
+
+    let token = if server_signing_enabled(env) {
+        let keyring = MasterKeyring::from_env(env)
+            .map_err(|err| Error::MasterKey(err.to_string()))?;
+        let key_set = get_signing_keys(env).await
+            .map_err(|_| Error::KvStore)?;
+
+        // Logins never create signing keys: the key set comes from the rotation endpoint or the
+        // scheduled rotation, and issuing fails until one exists
+        let signing_key = key_set.current().ok_or(Error::SigningKeysNotInitialized)?;
+        let private_key_der = signing_key_der(keyring.as_ref(), signing_key)
+            .map_err(|err| Error::JwtGeneration(err.to_string()))?;
+
+        generate_server_jwt_token(&claims, &signing_key.kid, &private_key_der)
+            .map_err(|err| Error::JwtGeneration(err.to_string()))?
+    } else {
+        let lookup_secret = token_lookup_secret(env)?;
+        generate_jwt_token(user_data, &claims, &lookup_secret)
+            .map_err(|err| Error::JwtGeneration(err.to_string()))?
+    };
+
+    Ok((token, expiration_minutes * 60))
+}
+
+// Promote the next signing key, retire the current one and prune keys whose tokens have expired
+async fn rotate_signing_keys(env: &Env) -> std::result::Result<RotationReport, Error> {
+    let keyring = MasterKeyring::from_env(env)
+        .map_err(|err| Error::MasterKey(err.to_string()))?;
+    let mut key_set = get_signing_keys(env).await
+        .map_err(|_| Error::KvStore)?;
+
+    // Retired keys must outlive the longest token they may have signed
+    let max_token_lifetime = jwt_expiration_minutes(env) * 60 + 60;
+    let report = key_set.rotate(keyring.as_ref(), Utc::now().timestamp(), max_token_lifetime)
+        .map_err(|err| Error::JwtGeneration(err.to_string()))?;
+
+    store_signing_keys(env, &key_set).await
+        .map_err(|_| Error::KvStore)?;
+
+    Ok(report)
+}
+
+// Server-wide key authenticating the KV lookup key carried by tokens (see token_lookup.rs)
+fn token_lookup_secret(env: &Env) -> std::result::Result<Vec<u8>, Error> {
+    env.secret("TOKEN_LOOKUP_SECRET")
+        .map(|secret| secret.to_string().into_bytes())
+        .map_err(|_| Error::MissingTokenLookupSecret)
+}
+
+// Read token lifetime from the environment, defaulting to 15 minutes
+fn jwt_expiration_minutes(env: &Env) -> i64 {
+    env_parse(env, "JWT_EXPIRATION_MINUTES", 15)
+}
+
+// Scopes issued when the client does not request any
+fn default_token_scopes(env: &Env) -> String {
+    env.var("DEFAULT_TOKEN_SCOPES")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| DEFAULT_SCOPES.to_string())
+}
+
+// Parse an OAuth style request body that may be form-encoded or JSON, an empty body yields the default
+async fn parse_form_or_json<T: serde::de::DeserializeOwned + Default>(req: &mut Request) -> std::result::Result<T, Error> {
+    let is_form = req
+        .headers()
+        .get("Content-Type")
+        .ok()
+        .flatten()
+        .map(|content_type| content_type.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+
+    let body = req
+        .text()
+        .await
+        .map_err(|err| Error::DecodeBody(err.to_string()))?;
+
+    if body.trim().is_empty() {
+        Ok(T::default())
+    } else if is_form {
+        serde_urlencoded::from_str(&body).map_err(|err| Error::DecodeBody(err.to_string()))
+    } else {
+        serde_json::from_str(&body).map_err(|err| Error::DecodeBody(err.to_string()))
+    }
+}
+
+// Admin routes take the ADMIN_API_KEY secret as a bearer token and do not exist without it
+fn authenticate_admin(req: &Request, env: &Env) -> std::result::Result<(), Error> {
+    let admin_key = env
+        .secret("ADMIN_API_KEY")
+        .map_err(|_| Error::InvalidRoute)?
+        .to_string();
+
+    let auth_header = req
+        .headers()
+        .get("Authorization")
+        .map_err(|_| Error::MissingAuthToken)?
+        .ok_or(Error::MissingAuthToken)?;
+
+    let presented = auth_header
+        .strip_prefix("Bearer ")
+        .ok_or(Error::InvalidAuthFormat)?;
+
+    if bool::from(presented.as_bytes().ct_eq(admin_key.as_bytes())) {
+        Ok(())
+    } else {
+        Err(Error::InvalidAdminKey)
+    }
+}
+
+// Check a password against the user's stored Argon2id hash
+fn verify_user_password(env: &Env, user_data: &UserData, password: &str) -> std::result::Result<bool, Error> {
+    let password_hash = PasswordHash::new(&user_data.password_hash)
+        .map_err(|err| Error::InvalidPasswordHash(err.to_string()))?;
+
+    let started = Utc::now().timestamp_millis();
+    let verified = Argon2::default().verify_password(password.as_bytes(), &password_hash);
+    let outcome = match &verified {
+        Ok(()) => "match",
+        Err(argon2::password_hash::Error::Password) => "mismatch",
+        Err(_) => "error",
+    };
+    Metrics::from_env(env).password_verify(outcome, Utc::now().timestamp_millis() - started);
+
+    match verified {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(err) => Err(Error::Verify(err.to_string())),
+    }
+}
+
+// Sensitive changes need the current password or a login within STEP_UP_MAX_AGE_MINUTES.
+// Returns true when the password was supplied and verified.
+fn require_step_up(
+    env: &Env,
+    user_data: &UserData,
+    auth: &AuthContext,
+    current_password: Option<&str>,
+) -> std::result::Result<bool, Error> {
+    if let Some(current_password) = current_password {
+        return if verify_user_password(env, user_data, current_password)? {
+            Ok(true)
+        } else {
+            Err(Error::InvalidCurrentPassword)
+        };
+    }
+
+    let max_age_seconds = env_parse::<i64>(env, "STEP_UP_MAX_AGE_MINUTES", 5) * 60;
+    let recently_authenticated = auth
+        .auth_time
+        .is_some_and(|auth_time| Utc::now().timestamp() - auth_time <= max_age_seconds);
+
+    if recently_authenticated {
+        Ok(false)
+    } else {
+        Err(Error::StepUpRequired(max_age_seconds))
+    }
+}
+
+// Authenticate the request's bearer token (JWT or personal access token) and enforce the route's scopes
+async fn authenticate_request(req: &Request, env: &Env, required_scopes: &[&str]) -> std::result::Result<(UserData, AuthContext), Error> {
+    // Get token from Authorization header
+    let auth_header = req
+        .headers()
+        .get("Authorization")
+        .map_err(|_| Error::MissingAuthToken)?;
+
+    let (token, from_cookie) = match auth_header {
+        // Extract token from "Bearer <token>" format
+        Some(auth_header) => {
+            let token = auth_header
+                .strip_prefix("Bearer ")
+                .ok_or(Error::InvalidAuthFormat)?;
+            (token.to_string(), false)
+        }
+        // Without one, fall back to the session cookie when cookie sessions are enabled
+        None if session_cookies_enabled(env) => {
+            (get_cookie(req, SESSION_COOKIE).ok_or(Error::MissingAuthToken)?, true)
+        }
+        None => return Err(Error::MissingAuthToken),
+    };
+
+    let lookup_secret = token_lookup_secret(env)?;
+    let (user_data, mut auth) = if is_access_token(&token) {
+        // Access tokens are never issued as cookies
+        if from_cookie {
+            return Err(Error::InvalidAccessToken);
+        }
+        authenticate_access_token(&token, env, &lookup_secret).await?
+    } else {
+        authenticate_jwt(&token, env, &lookup_secret).await?
+    };
+
+    if from_cookie {
+        // The browser attaches the cookie to cross-site requests too, only the CSRF token proves
+        // the request came from our frontend. Cookie tokens always carry one.
+        let expected = auth.csrf.as_deref().ok_or(Error::InvalidJwtToken)?;
+        if requires_csrf_check(&req.method()) {
+            let presented = req.headers().get(CSRF_HEADER).ok().flatten().unwrap_or_default();
+            if !verify_csrf_token(expected, &presented) {
+                return Err(Error::InvalidCsrfToken);
+            }
+        }
+    } else {
+        // A bearer token is never sent implicitly, no CSRF protection needed
+        auth.csrf = None;
+    }
+
+    // JWTs of an account scheduled for deletion are already invalid, access tokens are not
+    if user_data.pending_deletion.is_some() {
+        return Err(Error::AccountPendingDeletion);
+    }
+
+    // Check the token was granted everything this route requires
+    if !has_scopes(&auth.scope, required_scopes) {
+        return Err(Error::InsufficientScope(required_scopes.join(" ")));
+    }
+
+    Ok((user_data, auth))
+}
+
+// Verify a JWT, either against a server-managed key (EdDSA) or the unique secret of the user it names
+async fn authenticate_jwt(token: &str, env: &Env, lookup_secret: &[u8]) -> std::result::Result<(UserData, AuthContext), Error> {
+    let header = decode_header(token)
+        .map_err(|_| Error::InvalidJwtToken)?;
+
+    let (user_data, claims) = if header.alg == Algorithm::EdDSA {
+        // The signature is checked against the published key set before the user is loaded
+        let kid = header.kid.ok_or(Error::InvalidJwtToken)?;
+        let signing_key = find_verification_key(env, &kid).await
+            .map_err(|_| Error::KvStore)?
+            .ok_or(Error::InvalidJwtToken)?;
+
+        let claims = verify_jwt_token_with_server_key(token, &signing_key.public_key)
+            .map_err(|_| Error::InvalidJwtToken)?;
+
+        let user_data = get_user_from_kv(env, &claims.sub).await
+            .map_err(|_| Error::UserNotFound)?;
+
+        // Verify JWT version matches current user version
+        if claims.ver != user_data.jwt_version {
+            return Err(Error::InvalidJwtToken);
+        }
+
+        (user_data, claims)
+    } else {
+        // Extract username from token, rejecting it before any KV read unless its lookup tag is authentic
+        let username = extract_username_from_token(token, lookup_secret)
+            .map_err(|_| Error::InvalidJwtToken)?;
+
+        // Get user from KV store
+        let user_data = get_user_from_kv(env, &username).await
+            .map_err(|_| Error::UserNotFound)?;
+
+        // Verify JWT token using user's unique secret
+        let claims = verify_jwt_token_with_user_secret(token, &user_data).await
+            .map_err(|_| Error::InvalidJwtToken)?;
+
+        (user_data, claims)
+    };
+
+    // Check if token is expired
+    let current_time = Utc::now().timestamp() as usize;
+    if claims.exp < current_time {
+        return Err(Error::ExpiredJwtToken);
+    }
+
+    let auth = AuthContext {
+        username: claims.sub,
+        scope: claims.scope,
+        method: AuthMethod::Jwt,
+        auth_time: Some(claims.auth_time as i64).filter(|auth_time| *auth_time > 0),
+        csrf: Some(claims.csrf).filter(|csrf| !csrf.is_empty()),
+    };
+    Ok((user_data, auth))
+}
+
+// Verify a personal access token against the hash stored on its owner
+async fn authenticate_access_token(token: &str, env: &Env, lookup_secret: &[u8]) -> std::result::Result<(UserData, AuthContext), Error> {
+    // Forged token ids are rejected here, before they cost a KV read
+    let token_id = parse_access_token_id(token, lookup_secret)
+        .ok_or(Error::InvalidAccessToken)?;
+
+    let username = get_access_token_owner(env, token_id).await
+        .map_err(|_| Error::InvalidAccessToken)?;
+
+    let user_data = get_user_from_kv(env, &username).await
+        .map_err(|_| Error::InvalidAccessToken)?;
+
+    let record = user_data
+        .access_tokens
+        .iter()
+        .find(|record| record.id == token_id)
+        .ok_or(Error::InvalidAccessToken)?;
+
+    if !verify_access_token(token, record) {
+        return Err(Error::InvalidAccessToken);
+    }
+
+    if record.expires_at.is_some_and(|expires_at| expires_at < Utc::now().timestamp()) {
+        return Err(Error::ExpiredAccessToken);
+    }
+
+    let auth = AuthContext {
+        username: user_data.username.clone(),
+        scope: record.scope.clone(),
+        method: AuthMethod::AccessToken,
+        auth_time: None,
+        csrf: None,
+    };
+    Ok((user_data, auth))
+}
+
 #[event(fetch)]
-async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    // Enable CORS for all requests
-    let cors_headers = [
-        ("Access-Control-Allow-Origin", "*"),
-        ("Access-Control-Allow-Methods", "GET, POST, DELETE, PATCH, OPTIONS"),
-        ("Access-Control-Allow-Headers", "Content-Type, cf-turnstile-response, Authorization"),
-    ];
-
-    // Handle preflight requests
-    if req.method() == Method::Options {
-        return Response::empty()
-            .map(|mut res| {
-                for (key, value) in cors_headers.iter() {
-                    res.headers_mut().set(key, value).ok();
-                }
-                res            });
-    }
-
-    let result = match (req.method(), req.path().as_ref()) {
-        (Method::Post, "/login") => login_handler(req, env).await,
-        (Method::Post, "/register") => register_handler(req, env).await,
-        (Method::Delete, "/user") => delete_user_handler(req, env).await,
-        (Method::Patch, "/user") => update_user_handler(req, env).await,
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    logging::init(&env);
+    let started = Utc::now().timestamp_millis();
+
+    // Sent back in X-Request-ID and with every error so clients can quote it when reporting a
+    // problem, and attached to every log line of the request
+    let request_id = logging::request_id(&req);
+    let method = req.method();
+    let path = req.path();
+
+    // CORS policy from the CORS_* env vars
+    let cors = CorsPolicy::from_env(&env);
+    let origin = req.headers().get("Origin").ok().flatten();
+
+    // Handle preflight requests, refusing origins outside the allowlist
+    if method == Method::Options {
+        let mut response = match cors.preflight_headers(origin.as_deref()) {
+            Some(headers) => Response::empty()?.with_status(204).with_headers(headers),
+            None => {
+                let err = Error::CorsOriginNotAllowed;
+                err.log(&request_id);
+                err.to_response(&request_id)?
+            }
+        };
+        response.headers_mut().set(REQUEST_ID_HEADER, &request_id)?;
+        return Ok(response);
+    }
+
+    // Every state-changing route is audited, handlers fill in the user and failures main cannot see
+    let audit = AuditRecorder::new(&req, &request_id, audit_event_type(&method, &path));
+    let audit_env = env.clone();
+
+    let result = match (method.clone(), path.as_ref()) {
+        (Method::Post, "/login") => login_handler(req, env, &audit).await,
+        (Method::Post, "/register") => register_handler(req, env, &ctx, &audit).await,
+        (Method::Post, "/logout") => logout_handler(env).await,
+        (Method::Delete, "/user") => delete_user_handler(req, env, &ctx, &[ACCOUNT_DELETE], &audit).await,
+        (Method::Patch, "/user") => update_user_handler(req, env, &ctx, &[PROFILE_WRITE], &audit).await,
+        (Method::Post, "/user/restore") => restore_account_handler(req, env, &ctx, &audit).await,
+        (Method::Get, "/me") => me_handler(req, env, &[PROFILE_READ]).await,
+        (Method::Get, "/me/logins") => login_history_handler(req, env, &[PROFILE_READ]).await,
+        (Method::Get, "/me/audit") => my_audit_handler(req, env, &[PROFILE_READ]).await,
+        (Method::Get, "/me/export") => export_handler(req, env, &[PROFILE_READ], &audit).await,
+        (Method::Post, "/device/code") => device_code_handler(req, env).await,
+        (Method::Post, "/device/verify") => device_verify_handler(req, env, &[PROFILE_WRITE], &audit).await,
+        (Method::Post, "/token") => token_handler(req, env, &audit).await,
+        (Method::Post, "/tokens") => create_access_token_handler(req, env, &[PROFILE_WRITE], &audit).await,
+        (Method::Get, "/tokens") => list_access_tokens_handler(req, env, &[PROFILE_READ]).await,
+        (Method::Delete, path) if path.starts_with("/tokens/") => {
+            let token_id = path.trim_start_matches("/tokens/").to_string();
+            revoke_access_token_handler(req, env, &[PROFILE_WRITE], &token_id, &audit).await
+        }
+        (Method::Post, "/admin/reencrypt") => reencrypt_handler(req, env).await,
+        (Method::Post, "/admin/signing-keys/rotate") => rotate_signing_keys_handler(req, env).await,
+        (Method::Get, "/admin/audit") => admin_audit_handler(req, env).await,
+        (Method::Post, path) if path.starts_with("/admin/users/") && path.ends_with("/restore") => {
+            match path_param(path, "/admin/users/", "/restore") {
+                Some(username) => admin_restore_account_handler(req, env, &ctx, &username, &audit).await,
+                None => Err(Error::InvalidRoute),
+            }
+        }
+        (Method::Get, "/.well-known/jwks.json") => jwks_handler(env).await,
         (Method::Get, "/health") => health_handler().await,
         _ => Err(Error::InvalidRoute),
     };
 
-    match result {
-        Ok(mut response) => {
-            // Add CORS headers to successful responses
-            for (key, value) in cors_headers.iter() {
-                response.headers_mut().set(key, value).ok();
-            }
-            Ok(response)
+    let metrics = Metrics::from_env(&audit_env);
+    if let Some(event) = audit.finish(result.as_ref().err().map(Error::code)) {
+        // A wrong password is a 200 with success false, the audit outcome covers both kinds of failure
+        if event.event_type == AuditEventType::Login {
+            metrics.login(event.outcome == AuditOutcome::Success, event.reason.as_deref());
         }
+        run_in_background(&audit_env, &ctx, Task::WriteAuditEvent { event }).await;
+    }
+    let error_code = result.as_ref().err().map(Error::code);
+
+    let mut response = match result {
+        Ok(response) => response,
         Err(err) => {
-            let mut error_response = err.to_response()?;
-            // Add CORS headers to error responses
-            for (key, value) in cors_headers.iter() {
-                error_response.headers_mut().set(key, value).ok();
+            err.log(&request_id);
+            err.to_response(&request_id)?
+        }
+    };
+    // CORS headers go on successful and error responses alike
+    cors.apply(response.headers_mut(), origin.as_deref());
+    response.headers_mut().set(REQUEST_ID_HEADER, &request_id)?;
+
+    let duration_ms = Utc::now().timestamp_millis() - started;
+    // Unknown paths share one label so scanners cannot blow up the route cardinality
+    let route = if error_code == Some(Error::InvalidRoute.code()) { "unmatched".to_string() } else { route_template(&path) };
+    metrics.request(method.as_ref(), &route, response.status_code(), error_code, duration_ms);
+
+    logging::info("request", serde_json::json!({
+        "request_id": request_id,
+        "method": method.to_string(),
+        "path": path,
+        "status": response.status_code(),
+        "duration_ms": duration_ms
+    }));
+    Ok(response)
+}
+
+// Consumer of the TASKS_QUEUE queue, see tasks.rs
+#[event(queue)]
+async fn queue_consumer(batch: MessageBatch<TaskMessage>, env: Env, _ctx: Context) -> Result<()> {
+    logging::init(&env);
+
+    // Retries go back onto the queue the message came from
+    let queue = env.queue("TASKS_QUEUE").ok().map(TaskQueue::Cloudflare);
+
+    for message in batch.iter() {
+        // A body that does not parse can never succeed, it is acknowledged with the batch
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                logging::error("dropping malformed task message", serde_json::json!({ "error": err.to_string() }));
+                continue;
+            }
+        };
+
+        let processed = match &queue {
+            Some(queue) => process_task(&env, queue, message.body()).await,
+            None => Err("TASKS_QUEUE is not bound, retries cannot be scheduled".into()),
+        };
+        match processed {
+            Ok(()) => message.ack(),
+            Err(err) => {
+                // Let the queue redeliver it, its own max_retries and dead letter queue still apply
+                logging::error("task redelivered", serde_json::json!({
+                    "message_id": message.id(),
+                    "error": err.to_string()
+                }));
+                message.retry();
             }
-            Ok(error_response)
         }
     }
+    Ok(())
+}
+
+// Cron triggers run the maintenance jobs enabled in CRON_JOBS, see maintenance.rs
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, ctx: ScheduleContext) {
+    logging::init(&env);
+
+    let cron = event.cron();
+    for job in jobs_for_cron(&env, &cron) {
+        let started = Utc::now().timestamp_millis();
+        let (status, details) = match run_maintenance_job(&env, &ctx, job).await {
+            Ok(Some(details)) => (JobStatus::Ok, details),
+            Ok(None) => (JobStatus::Skipped, serde_json::Value::Null),
+            Err(err) => {
+                err.log(&format!("cron:{}", job.name()));
+                (JobStatus::Failed, serde_json::json!({ "error": err.code() }))
+            }
+        };
+
+        let report = JobReport {
+            job: job.name(),
+            cron: cron.clone(),
+            status,
+            details,
+            duration_ms: Utc::now().timestamp_millis() - started,
+        };
+        let level = match report.status {
+            JobStatus::Failed => logging::Level::Error,
+            _ => logging::Level::Info,
+        };
+        logging::log(level, "maintenance job finished", serde_json::to_value(&report).unwrap_or_default());
+    }
+}
+
+// Run one maintenance job, None when there was nothing for it to do in this configuration
+async fn run_maintenance_job(
+    env: &Env,
+    ctx: &ScheduleContext,
+    job: MaintenanceJob,
+) -> std::result::Result<Option<serde_json::Value>, Error> {
+    let details = match job {
+        MaintenanceJob::RotateSigningKeys => {
+            if !server_signing_enabled(env) {
+                return Ok(None);
+            }
+            serde_json::to_value(rotate_signing_keys(env).await?)
+        }
+        MaintenanceJob::PruneAccessTokens => {
+            let report = prune_expired_access_tokens(env, Utc::now().timestamp()).await
+                .map_err(|_| Error::KvStore)?;
+            serde_json::to_value(report)
+        }
+        MaintenanceJob::PurgeDeletedAccounts => {
+            let report = purge_deleted_accounts(env, Utc::now().timestamp()).await
+                .map_err(|_| Error::KvStore)?;
+            for username in &report.purged_usernames {
+                dispatch_webhook(env, ctx, USER_DELETED, serde_json::json!({ "username": username })).await;
+            }
+            serde_json::to_value(report)
+        }
+    };
+    details
+        .map(Some)
+        .map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+// The single path segment between `prefix` and `suffix`, percent-decoded. None when there is
+// more than one segment or the decoded bytes are not UTF-8.
+fn path_param(path: &str, prefix: &str, suffix: &str) -> Option<String> {
+    let segment = path.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    if segment.contains('/') {
+        return None;
+    }
+    percent_decode(segment)
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The audit event a route records unless its handler says otherwise, None for read-only routes
+fn audit_event_type(method: &Method, path: &str) -> Option<AuditEventType> {
+    match (method, path) {
+        (Method::Post, "/login") => Some(AuditEventType::Login),
+        (Method::Post, "/register") => Some(AuditEventType::Register),
+        (Method::Post, "/logout") => Some(AuditEventType::Logout),
+        (Method::Delete, "/user") => Some(AuditEventType::AccountDelete),
+        (Method::Post, "/user/restore") => Some(AuditEventType::AccountRestore),
+        (Method::Post, path) if path.starts_with("/admin/users/") && path.ends_with("/restore") => {
+            Some(AuditEventType::AccountRestore)
+        }
+        (Method::Patch, "/user") => Some(AuditEventType::AccountUpdate),
+        (Method::Get, "/me/export") => Some(AuditEventType::DataExport),
+        (Method::Post, "/device/code") => Some(AuditEventType::DeviceCodeIssue),
+        (Method::Post, "/device/verify") => Some(AuditEventType::DeviceApprove),
+        (Method::Post, "/token") => Some(AuditEventType::DeviceTokenIssue),
+        (Method::Post, "/tokens") => Some(AuditEventType::AccessTokenCreate),
+        (Method::Delete, path) if path.starts_with("/tokens/") => Some(AuditEventType::AccessTokenRevoke),
+        (Method::Post, "/admin/reencrypt") => Some(AuditEventType::MasterKeyReencrypt),
+        (Method::Post, "/admin/signing-keys/rotate") => Some(AuditEventType::SigningKeyRotate),
+        _ => None,
+    }
+}
+
+// Hand a side effect to the task queue, or run it after the response when there is none (or the
+// queue refused it). Side effects never fail the request that caused them.
+async fn run_in_background(env: &Env, ctx: &impl WaitUntil, task: Task) {
+    let Some(queue) = TaskQueue::from_env(env) else {
+        ctx.keep_alive(run_inline(env.clone(), task));
+        return;
+    };
+
+    match queue.enqueue(&TaskMessage::new(task.clone()), 0).await {
+        Ok(()) if queue.is_memory() => ctx.keep_alive(drain_memory_queue(env.clone())),
+        Ok(()) => {}
+        Err(err) => {
+            logging::error("failed to enqueue task, running it inline", serde_json::json!({ "error": err.to_string() }));
+            ctx.keep_alive(run_inline(env.clone(), task));
+        }
+    }
+}
+
+// Send an account lifecycle webhook to every configured endpoint, in the background.
+// A misconfiguration is logged rather than failing the request.
+async fn dispatch_webhook(env: &Env, ctx: &impl WaitUntil, event_type: &str, data: serde_json::Value) {
+    let config = match WebhookConfig::from_env(env) {
+        Ok(Some(config)) if config.wants(event_type) => config,
+        Ok(_) => return,
+        Err(err) => {
+            logging::error("webhook not sent", serde_json::json!({
+                "event_type": event_type,
+                "error": err.to_string()
+            }));
+            return;
+        }
+    };
+
+    let event = WebhookEvent::new(event_type, data);
+    for url in config.urls {
+        run_in_background(env, ctx, Task::DeliverWebhook { url, event: event.clone() }).await;
+    }
 }
 
-async fn login_handler(mut req: Request, env: Env) -> std::result::Result<Response, Error> {
-    // Get Turnstile token from header
-    let turnstile_token = req
+// Check the CAPTCHA token in the cf-turnstile-response header with the configured provider,
+// `action` names the endpoint
+async fn verify_human(req: &Request, env: &Env, action: &str) -> std::result::Result<(), Error> {
+    let result = check_captcha_token(req, env, action).await;
+    let outcome = result.as_ref().err().map(Error::code).unwrap_or("ok");
+    Metrics::from_env(env).captcha(provider_name(env), action, outcome);
+    result
+}
+
+async fn check_captcha_token(req: &Request, env: &Env, action: &str) -> std::result::Result<(), Error> {
+    // Get the token from header (same header whatever the provider)
+    let captcha_token = req
         .headers()
         .get("cf-turnstile-response")
         .map_err(|_| Error::MissingTurnstileToken)?
         .ok_or(Error::MissingTurnstileToken)?;
 
-    // Get Turnstile secret from environment
-    let turnstile_secret = env
-        .secret("TURNSTILE_SECRET_KEY")
-        .map_err(|_| Error::MissingTurnstileSecret)?
-        .to_string();
+    // Provider and its secret from environment
+    let verifier = CaptchaVerifier::from_env(env)
+        .map_err(Error::MissingCaptchaSecret)?;
 
-    // Verify Turnstile token
-    verify_turnstile_token(&turnstile_token, &turnstile_secret).await
-        .map_err(|_| Error::InvalidTurnstileToken)?;
+    if verifier.is_stub() {
+        logging::warn("CAPTCHA stub provider in use, bot protection is disabled", serde_json::json!({ "action": action }));
+    }
+
+    let options = VerifyOptions::from_request(req, env, action);
+
+    let challenge = verifier.verify(&captcha_token, &options).await
+        .map_err(Error::HumanVerification)?;
+
+    // Each token may be redeemed once, only tokens the provider accepted are recorded
+    let first_use = redeem_token(env, &captcha_token, &challenge, &options).await
+        .map_err(|_| Error::KvStore)?;
+    if !first_use {
+        return Err(Error::CaptchaTokenReused);
+    }
 
+    Ok(())
+}
+
+// With risk-based challenges enabled, only risky requests have to solve a CAPTCHA (a token sent
+// anyway is still verified). Otherwise every request does.
+async fn challenge_if_risky(
+    req: &Request,
+    env: &Env,
+    risk: &RiskConfig,
+    action: &str,
+    username: Option<&str>,
+) -> std::result::Result<(), Error> {
+    if !risk.enabled {
+        return verify_human(req, env, action).await;
+    }
+
+    let device_secret = token_lookup_secret(env)?;
+    let assessment = assess_risk(req, env, risk, &device_secret, username).await
+        .map_err(|_| Error::KvStore)?;
+    let has_captcha_token = req.headers().get("cf-turnstile-response").ok().flatten().is_some();
+
+    if assessment.challenge_required {
+        logging::info("risk challenge", serde_json::json!({
+            "action": action,
+            "score": assessment.score,
+            "reasons": assessment.reasons
+        }));
+        if !has_captcha_token {
+            return Err(Error::ChallengeRequired);
+        }
+    }
+
+    if has_captcha_token {
+        verify_human(req, env, action).await
+    } else {
+        Ok(())
+    }
+}
+
+async fn login_handler(mut req: Request, env: Env, audit: &AuditRecorder) -> std::result::Result<Response, Error> {
     // Parse login request
     let login_req: LoginRequest = req
         .json()
         .await
         .map_err(|err| Error::DecodeBody(err.to_string()))?;
+    audit.set_username(&login_req.user);
+
+    // Validate the requested scopes before doing any expensive work
+    let scope = resolve_scopes(login_req.scope.as_deref(), &default_token_scopes(&env))
+        .map_err(Error::InvalidScope)?;
+
+    // Verify the CAPTCHA was solved for this endpoint, when required
+    let risk = RiskConfig::from_env(&env);
+    challenge_if_risky(&req, &env, &risk, "login", Some(&login_req.user)).await?;
 
     // Get user from KV store
-    let user_data = get_user_from_kv(&env, &login_req.user).await
-        .map_err(|_| Error::UserNotFound)?;
+    let user_data = match get_user_from_kv(&env, &login_req.user).await {
+        Ok(user_data) => user_data,
+        Err(_) => {
+            if risk.enabled {
+                record_login_failure(&req, &env, &risk, &login_req.user).await
+                    .map_err(|_| Error::KvStore)?;
+            }
+            return Err(Error::UserNotFound);
+        }
+    };
+    audit.set_user(&user_data);
 
     // Verify password
-    let password_hash = PasswordHash::new(&user_data.password_hash)
-        .map_err(|err| Error::InvalidPasswordHash(err.to_string()))?;
+    if !verify_user_password(&env, &user_data, &login_req.password)? {
+        if risk.enabled {
+            record_login_failure(&req, &env, &risk, &login_req.user).await
+                .map_err(|_| Error::KvStore)?;
+        }
+        audit.fail("invalid_credentials");
+        record_login_history(&req, &env, &user_data.username, false).await;
 
-    let argon2 = Argon2::default();
-    match argon2.verify_password(login_req.password.as_bytes(), &password_hash) {
-        Ok(()) => {
-            // Password is correct, generate JWT using user's unique secret
-            let expiration_minutes = env
-                .var("JWT_EXPIRATION_MINUTES")
-                .map(|v| v.to_string().parse::<i64>().unwrap_or(15))
-                .unwrap_or(15);
-
-            let token = generate_jwt_token(&user_data, expiration_minutes)
-                .map_err(|err| Error::JwtGeneration(err.to_string()))?;
-
-            let response = LoginResponse {
-                success: true,
-                token: Some(token),
-                message: "Login successful".to_string(),
-                expires_in: expiration_minutes * 60, // Convert to seconds
-            };
+        let response = LoginResponse {
+            success: false,
+            token: None,
+            message: "Invalid credentials".to_string(),
+            expires_in: 0,
+            scope: None,
+            csrf_token: None,
+            device_token: None,
+        };
+        return Response::from_json(&response)
+            .map_err(|err| Error::EncodeBody(err.to_string()));
+    }
 
-            Response::from_json(&response)
-                .map_err(|err| Error::EncodeBody(err.to_string()))
-        }
-        Err(argon2::password_hash::Error::Password) => {
-            let response = LoginResponse {
-                success: false,
-                token: None,
-                message: "Invalid credentials".to_string(),
-                expires_in: 0,
-            };
-            Response::from_json(&response)
-                .map_err(|err| Error::EncodeBody(err.to_string()))
-        }
-        Err(err) => Err(Error::Verify(err.to_string())),
+    record_login_history(&req, &env, &user_data.username, true).await;
+
+    // Only reveal the pending deletion to someone who knows the password
+    if user_data.pending_deletion.is_some() {
+        return Err(Error::AccountPendingDeletion);
     }
-}
 
-async fn register_handler(mut req: Request, env: Env) -> std::result::Result<Response, Error> {
-    // Get Turnstile token from header
-    let turnstile_token = req
-        .headers()
-        .get("cf-turnstile-response")
-        .map_err(|_| Error::MissingTurnstileToken)?
-        .ok_or(Error::MissingTurnstileToken)?;
+    // Remember this device and IP so the next login from them is low risk
+    let device_token = if risk.enabled {
+        record_login_success(&req, &env, &user_data.username).await
+            .map_err(|_| Error::KvStore)?;
+        Some(generate_device_token(&token_lookup_secret(&env)?, &user_data.username))
+    } else {
+        None
+    };
 
-    // Get Turnstile secret from environment
-    let turnstile_secret = env
-        .secret("TURNSTILE_SECRET_KEY")
-        .map_err(|_| Error::MissingTurnstileSecret)?
-        .to_string();
+    // Password is correct, generate JWT using user's unique secret. In cookie mode the token
+    // goes into the HttpOnly cookie only, scripts get the CSRF token instead.
+    let csrf_token = session_cookies_enabled(&env).then(generate_csrf_token);
+    let (token, expires_in) = issue_jwt_token(
+        &env,
+        &user_data,
+        &scope,
+        Utc::now().timestamp(),
+        csrf_token.as_deref().unwrap_or_default(),
+    ).await?;
 
-    // Verify Turnstile token
-    verify_turnstile_token(&turnstile_token, &turnstile_secret).await
-        .map_err(|_| Error::InvalidTurnstileToken)?;
+    let response = LoginResponse {
+        success: true,
+        token: if csrf_token.is_some() { None } else { Some(token.clone()) },
+        message: "Login successful".to_string(),
+        expires_in,
+        scope: Some(scope),
+        csrf_token: csrf_token.clone(),
+        device_token: device_token.clone(),
+    };
 
+    let mut response = Response::from_json(&response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    if let Some(csrf_token) = &csrf_token {
+        set_session_cookies(&env, response.headers_mut(), &token, csrf_token, expires_in)
+            .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    }
+    if let Some(device_token) = &device_token {
+        response.headers_mut().append("Set-Cookie", &device_cookie(device_token))
+            .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    }
+    Ok(response)
+}
+
+// The login history is informational, failing to update it must not fail the login
+async fn record_login_history(req: &Request, env: &Env, username: &str, success: bool) {
+    if let Err(err) = record_login_attempt(env, username, LoginRecord::from_request(req, success)).await {
+        logging::error("failed to record login history", serde_json::json!({ "error": err.to_string() }));
+    }
+}
+
+async fn register_handler(
+    mut req: Request,
+    env: Env,
+    ctx: &Context,
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
     // Parse register request
     let register_req: LoginRequest = req
         .json()
         .await
         .map_err(|err| Error::DecodeBody(err.to_string()))?;
+    audit.set_username(&register_req.user);
+
+    validate_username(&register_req.user)?;
+
+    // Verify the CAPTCHA was solved for this endpoint, when required
+    let risk = RiskConfig::from_env(&env);
+    challenge_if_risky(&req, &env, &risk, "register", None).await?;
+    if risk.enabled {
+        record_registration(&req, &env, &risk).await
+            .map_err(|_| Error::KvStore)?;
+    }
 
     // Check if user already exists
-    if let Ok(_) = get_user_from_kv(&env, &register_req.user).await {
+    if get_user_from_kv(&env, &register_req.user).await.is_ok() {
+        audit.fail("username_exists");
         return Response::from_json(&serde_json::json!({
             "success": false,
             "message": "User already exists"
@@ -227,8 +1049,15 @@ async fn register_handler(mut req: Request, env: Env) -> std::result::Result<Res
         created_at: Utc::now().timestamp(),
         jwt_secret: generate_jwt_secret(),
         jwt_version: 1,
-    };    store_user_in_kv(&env, &register_req.user, &user_data).await
-        .map_err(|_| Error::KvStoreError)?;
+        access_tokens: Vec::new(),
+        pending_deletion: None,
+        account_id: generate_account_id(),
+    };
+    store_user_in_kv(&env, &register_req.user, &user_data).await
+        .map_err(|_| Error::KvStore)?;
+    audit.set_user(&user_data);
+
+    dispatch_webhook(&env, ctx, USER_CREATED, serde_json::json!({ "username": user_data.username })).await;
 
     Response::from_json(&serde_json::json!({
         "success": true,
@@ -236,100 +1065,184 @@ async fn register_handler(mut req: Request, env: Env) -> std::result::Result<Res
     })).map_err(|err| Error::EncodeBody(err.to_string()))
 }
 
-// Extract username from JWT token (without verification)
-fn extract_username_from_token(token: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+// Extract username from JWT token. The signature can only be checked once the user's secret is
+// loaded, so the `sub` is trusted only if the header's lookup tag (kid) proves we issued it.
+fn extract_username_from_token(token: &str, lookup_secret: &[u8]) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let header = decode_header(token)?;
+    let tag = header.kid.ok_or("Token has no lookup tag")?;
+
     // Decode without verification to get the username
     let mut validation = Validation::default();
     validation.insecure_disable_signature_validation();
-    
+
     let token_data = decode::<Claims>(
         token,
         &DecodingKey::from_secret("dummy".as_ref()),
         &validation,
     )?;
-    
+
+    if !verify_lookup_tag(lookup_secret, JWT_LOOKUP_CONTEXT, &token_data.claims.sub, &tag) {
+        return Err("Token lookup tag mismatch".into());
+    }
+
     Ok(token_data.claims.sub)
 }
 
-async fn delete_user_handler(req: Request, env: Env) -> std::result::Result<Response, Error> {
-    // Get JWT token from Authorization header
-    let auth_header = req
-        .headers()
-        .get("Authorization")
-        .map_err(|_| Error::MissingAuthToken)?
-        .ok_or(Error::MissingAuthToken)?;
+async fn delete_user_handler(
+    mut req: Request,
+    env: Env,
+    ctx: &Context,
+    required_scopes: &[&str],
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    // Verify the bearer token and resolve the account it belongs to
+    let (mut user_data, auth) = authenticate_request(&req, &env, required_scopes).await?;
+    audit.set_user(&user_data);
 
-    // Extract token from "Bearer <token>" format
-    let token = if auth_header.starts_with("Bearer ") {
-        &auth_header[7..]
-    } else {
-        return Err(Error::InvalidAuthFormat);
-    };
+    // Deleting the account needs the current password or a fresh login
+    let delete_req: DeleteUserRequest = parse_form_or_json(&mut req).await?;
+    require_step_up(&env, &user_data, &auth, delete_req.current_password.as_deref())?;
 
-    // Extract username from token to get user data
-    let username = extract_username_from_token(token)
-        .map_err(|_| Error::InvalidJwtToken)?;
-
-    // Get user from KV store
-    let user_data = get_user_from_kv(&env, &username).await
-        .map_err(|_| Error::UserNotFound)?;
+    // Without a grace period the account is gone at once
+    let grace_period = grace_period_seconds(&env);
+    if grace_period == 0 {
+        purge_user(&env, &user_data).await
+            .map_err(|_| Error::KvStore)?;
+        dispatch_webhook(&env, ctx, USER_DELETED, serde_json::json!({ "username": auth.username })).await;
 
-    // Verify JWT token using user's unique secret
-    let claims = verify_jwt_token_with_user_secret(token, &user_data).await
-        .map_err(|_| Error::InvalidJwtToken)?;
+        let response = DeleteResponse {
+            success: true,
+            message: format!("User '{}' deleted successfully", auth.username),
+            purge_at: None,
+            restore_token: None,
+            restore_url: None,
+        };
+        return delete_response(&env, &auth, &response);
+    }
 
-    // Check if token is expired
-    let current_time = Utc::now().timestamp() as usize;
-    if claims.exp < current_time {
-        return Err(Error::ExpiredJwtToken);
+    // Otherwise mark it for deletion, invalidate every JWT by rotating the secret and version and
+    // revoke the access tokens. Restoring the account does not bring them back.
+    let pending = schedule_deletion(&mut user_data, Utc::now().timestamp(), grace_period);
+    user_data.jwt_secret = generate_jwt_secret();
+    user_data.jwt_version += 1;
+    let revoked_tokens = std::mem::take(&mut user_data.access_tokens);
+    // Index first: an entry without a matching pending deletion is skipped by the purge job,
+    // a pending deletion without an entry would never be purged
+    add_to_purge_index(&env, &auth.username, pending.purge_at).await
+        .map_err(|_| Error::KvStore)?;
+    store_user_in_kv(&env, &auth.username, &user_data).await
+        .map_err(|_| Error::KvStore)?;
+    // The tokens are already gone from the record, leftover entries no longer authenticate
+    for token in &revoked_tokens {
+        delete_access_token_entries(&env, token).await
+            .map_err(|_| Error::KvStore)?;
     }
 
-    // Delete user from KV store
-    delete_user_from_kv(&env, &claims.sub).await
-        .map_err(|_| Error::UserNotFound)?;
+    let restore_token = generate_restore_token(&token_lookup_secret(&env)?, &user_data, pending.purge_at);
+    let restore_url = env
+        .var("ACCOUNT_RESTORE_URI")
+        .ok()
+        .map(|uri| format!("{}?token={}", uri, restore_token));
+
+    dispatch_webhook(&env, ctx, USER_DELETION_SCHEDULED, serde_json::json!({
+        "username": auth.username,
+        "purge_at": pending.purge_at
+    })).await;
 
     let response = DeleteResponse {
         success: true,
-        message: format!("User '{}' deleted successfully", claims.sub),
+        message: format!("User '{}' scheduled for deletion", auth.username),
+        purge_at: Some(pending.purge_at),
+        restore_token: Some(restore_token),
+        restore_url,
     };
+    delete_response(&env, &auth, &response)
+}
 
-    Response::from_json(&response)
-        .map_err(|err| Error::EncodeBody(err.to_string()))
+// The session of a cookie client is gone either way, clear its cookies
+fn delete_response(env: &Env, auth: &AuthContext, response: &DeleteResponse) -> std::result::Result<Response, Error> {
+    let mut response = Response::from_json(response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    if auth.csrf.is_some() {
+        clear_session_cookies(env, response.headers_mut())
+            .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    }
+    Ok(response)
 }
 
-async fn update_user_handler(mut req: Request, env: Env) -> std::result::Result<Response, Error> {
-    // Get JWT token from Authorization header
-    let auth_header = req
-        .headers()
-        .get("Authorization")
-        .map_err(|_| Error::MissingAuthToken)?
-        .ok_or(Error::MissingAuthToken)?;
+// Undo a pending deletion with the restore token handed out by DELETE /user. The token is the
+// credential, the account's tokens stay invalid and the user logs in again.
+async fn restore_account_handler(
+    mut req: Request,
+    env: Env,
+    ctx: &Context,
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    let restore_req: RestoreAccountRequest = parse_form_or_json(&mut req).await?;
 
-    // Extract token from "Bearer <token>" format
-    let token = if auth_header.starts_with("Bearer ") {
-        &auth_header[7..]
-    } else {
-        return Err(Error::InvalidAuthFormat);
-    };
+    let username = restore_token_username(&restore_req.token).ok_or(Error::InvalidRestoreToken)?;
+    let mut user_data = get_user_from_kv(&env, &username).await
+        .map_err(|_| Error::InvalidRestoreToken)?;
+    if !verify_restore_token(&token_lookup_secret(&env)?, &user_data, &restore_req.token, Utc::now().timestamp()) {
+        return Err(Error::InvalidRestoreToken);
+    }
+    audit.set_user(&user_data);
 
-    // Extract username from token to get user data
-    let username = extract_username_from_token(token)
-        .map_err(|_| Error::InvalidJwtToken)?;
+    restore_account(&env, ctx, &mut user_data).await
+}
 
-    // Get user from KV store
-    let mut user_data = get_user_from_kv(&env, &username).await
+// Restore an account scheduled for deletion, for support requests
+async fn admin_restore_account_handler(
+    req: Request,
+    env: Env,
+    ctx: &Context,
+    username: &str,
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    authenticate_admin(&req, &env)?;
+    validate_username(username)?;
+    audit.set_username(username);
+
+    let mut user_data = get_user_from_kv(&env, username).await
         .map_err(|_| Error::UserNotFound)?;
+    audit.set_user(&user_data);
+    if user_data.pending_deletion.is_none() {
+        return Err(Error::AccountNotPendingDeletion);
+    }
 
-    // Verify JWT token using user's unique secret
-    let claims = verify_jwt_token_with_user_secret(token, &user_data).await
-        .map_err(|_| Error::InvalidJwtToken)?;
+    restore_account(&env, ctx, &mut user_data).await
+}
 
-    // Check if token is expired
-    let current_time = Utc::now().timestamp() as usize;
-    if claims.exp < current_time {
-        return Err(Error::ExpiredJwtToken);
+async fn restore_account(env: &Env, ctx: &Context, user_data: &mut UserData) -> std::result::Result<Response, Error> {
+    let pending = user_data.pending_deletion.take();
+    store_user_in_kv(env, &user_data.username, user_data).await
+        .map_err(|_| Error::KvStore)?;
+    // A leftover entry would only be skipped by the purge job, so a failure here is not fatal
+    if let Some(pending) = pending {
+        if let Err(err) = remove_from_purge_index(env, &user_data.username, pending.purge_at).await {
+            logging::warn("failed to remove purge index entry", serde_json::json!({ "error": err.to_string() }));
+        }
     }
 
+    dispatch_webhook(env, ctx, USER_RESTORED, serde_json::json!({ "username": user_data.username })).await;
+
+    Response::from_json(&serde_json::json!({
+        "success": true,
+        "message": format!("User '{}' restored, log in again", user_data.username)
+    })).map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+async fn update_user_handler(
+    mut req: Request,
+    env: Env,
+    ctx: &Context,
+    required_scopes: &[&str],
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    // Verify the bearer token and resolve the account it belongs to
+    let (mut user_data, auth) = authenticate_request(&req, &env, required_scopes).await?;
+    audit.set_user(&user_data);
+
     // Parse update request
     let update_req: UpdateUserRequest = req
         .json()
@@ -338,6 +1251,7 @@ async fn update_user_handler(mut req: Request, env: Env) -> std::result::Result<
 
     // Validate that at least one field is being updated
     if update_req.new_username.is_none() && update_req.new_password.is_none() {
+        audit.fail("no_changes");
         return Response::from_json(&UpdateUserResponse {
             success: false,
             message: "At least one field (new_username or new_password) must be provided".to_string(),
@@ -346,6 +1260,19 @@ async fn update_user_handler(mut req: Request, env: Env) -> std::result::Result<
         }).map_err(|err| Error::EncodeBody(err.to_string()));
     }
 
+    if let Some(new_username) = &update_req.new_username {
+        validate_username(new_username)?;
+    }
+
+    match (update_req.new_username.is_some(), update_req.new_password.is_some()) {
+        (true, false) => audit.set_event_type(AuditEventType::UsernameChange),
+        (false, true) => audit.set_event_type(AuditEventType::PasswordChange),
+        _ => {}
+    }
+
+    // Username and password changes need the current password or a fresh login
+    let password_verified = require_step_up(&env, &user_data, &auth, update_req.current_password.as_deref())?;
+
     let mut jwt_rotated = false;
 
     // Update password if provided (this rotates JWT)
@@ -369,8 +1296,13 @@ async fn update_user_handler(mut req: Request, env: Env) -> std::result::Result<
     // Update username in user data if provided (this also rotates JWT)
     let new_username = update_req.new_username.as_deref();
     if let Some(new_user) = new_username {
+        // Accounts older than account ids keep the username they had as their id, so their audit
+        // events stay with them and never pass to whoever registers the name next
+        if user_data.account_id.is_empty() {
+            user_data.account_id = user_data.username.clone();
+        }
         user_data.username = new_user.to_string();
-        
+
         // Rotate JWT secret and version when username changes
         if !jwt_rotated {
             user_data.jwt_secret = generate_jwt_secret();
@@ -380,30 +1312,56 @@ async fn update_user_handler(mut req: Request, env: Env) -> std::result::Result<
     }
 
     // Update user in KV store
-    update_user_in_kv(&env, &claims.sub, new_username, &user_data).await
+    update_user_in_kv(&env, &auth.username, new_username, &user_data).await
         .map_err(|err| {
             if err.to_string().contains("already exists") {
                 Error::UsernameExists
             } else {
-                Error::KvStoreError
+                Error::KvStore
             }
         })?;
 
-    // Generate new JWT token since we rotated the secret
-    let (new_token, expires_in) = if jwt_rotated {
-        let expiration_minutes = env
-            .var("JWT_EXPIRATION_MINUTES")
-            .map(|v| v.to_string().parse::<i64>().unwrap_or(15))
-            .unwrap_or(15);
+    // Personal access tokens keep working across renames, point them at the new username
+    if let Some(new_user) = new_username {
+        for access_token in &user_data.access_tokens {
+            store_access_token_owner(&env, &access_token.id, new_user).await
+                .map_err(|_| Error::KvStore)?;
+        }
+        rename_login_history(&env, &auth.username, new_user).await
+            .map_err(|_| Error::KvStore)?;
 
-        let token = generate_jwt_token(&user_data, expiration_minutes)
-            .map_err(|err| Error::JwtGeneration(err.to_string()))?;
+        dispatch_webhook(&env, ctx, USER_USERNAME_CHANGED, serde_json::json!({
+            "username": new_user,
+            "previous_username": auth.username
+        })).await;
+    }
+    if update_req.new_password.is_some() {
+        dispatch_webhook(&env, ctx, USER_PASSWORD_CHANGED, serde_json::json!({ "username": user_data.username })).await;
+    }
+
+    // Generate new JWT token since we rotated the secret
+    let (mut new_token, expires_in) = if jwt_rotated {
+        // The replacement token keeps the scopes of the one it replaces, and counts as a fresh
+        // authentication only if the current password was just verified
+        let auth_time = if password_verified {
+            Utc::now().timestamp()
+        } else {
+            auth.auth_time.unwrap_or(0)
+        };
+        // A cookie session stays a cookie session, with the same CSRF token
+        let csrf = auth.csrf.as_deref().unwrap_or_default();
+        let (token, expires_in) = issue_jwt_token(&env, &user_data, &auth.scope, auth_time, csrf).await?;
 
-        (Some(token), Some(expiration_minutes * 60))
+        (Some(token), Some(expires_in))
     } else {
         (None, None)
     };
 
+    let session_token = match &auth.csrf {
+        Some(csrf) => new_token.take().zip(expires_in).map(|(token, expires_in)| (token, csrf.clone(), expires_in)),
+        None => None,
+    };
+
     let message = match (update_req.new_username.is_some(), update_req.new_password.is_some()) {
         (true, true) => "Username and password updated successfully. Previous tokens are now invalid.",
         (true, false) => "Username updated successfully. Previous tokens are now invalid.",
@@ -418,10 +1376,463 @@ async fn update_user_handler(mut req: Request, env: Env) -> std::result::Result<
         expires_in,
     };
 
+    let mut response = Response::from_json(&response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    if let Some((token, csrf, expires_in)) = session_token {
+        set_session_cookies(&env, response.headers_mut(), &token, &csrf, expires_in)
+            .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    }
+    Ok(response)
+}
+
+// Clear the session cookies. Tokens are stateless, so this ends the session in this browser
+// only; changing the password invalidates every token.
+async fn logout_handler(env: Env) -> std::result::Result<Response, Error> {
+    let mut response = Response::from_json(&serde_json::json!({
+        "success": true,
+        "message": "Logged out"
+    })).map_err(|err| Error::EncodeBody(err.to_string()))?;
+
+    clear_session_cookies(&env, response.headers_mut())
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    Ok(response)
+}
+
+async fn me_handler(req: Request, env: Env, required_scopes: &[&str]) -> std::result::Result<Response, Error> {
+    // Same verification as every other authenticated route, the profile comes from KV rather than the token
+    let (user_data, _) = authenticate_request(&req, &env, required_scopes).await?;
+
+    let response = ProfileResponse {
+        success: true,
+        user: UserProfile::from(&user_data),
+    };
+
+    Response::from_json(&response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+// The caller's recent sign-ins and failed password attempts, newest first
+async fn login_history_handler(req: Request, env: Env, required_scopes: &[&str]) -> std::result::Result<Response, Error> {
+    let (_, auth) = authenticate_request(&req, &env, required_scopes).await?;
+
+    let response = LoginHistoryResponse {
+        success: true,
+        logins: get_login_history(&env, &auth.username).await
+            .map_err(|_| Error::KvStore)?,
+    };
+
+    Response::from_json(&response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+// The caller's own audit trail, newest first
+async fn my_audit_handler(req: Request, env: Env, required_scopes: &[&str]) -> std::result::Result<Response, Error> {
+    let (user_data, _) = authenticate_request(&req, &env, required_scopes).await?;
+    audit_query_response(&req, &env, Some(user_data.id())).await
+}
+
+// Everything held about the caller as one JSON document. A GET has no body, so step-up takes the
+// current password from the X-Current-Password header.
+async fn export_handler(req: Request, env: Env, required_scopes: &[&str], audit: &AuditRecorder) -> std::result::Result<Response, Error> {
+    let (user_data, auth) = authenticate_request(&req, &env, required_scopes).await?;
+    audit.set_user(&user_data);
+
+    let current_password = req.headers().get(CURRENT_PASSWORD_HEADER).ok().flatten();
+    require_step_up(&env, &user_data, &auth, current_password.as_deref())?;
+
+    let export = build_export(&env, &user_data, &auth, Utc::now().timestamp()).await
+        .map_err(|_| Error::KvStore)?;
+
+    let mut response = Response::from_json(&export)
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    let headers = response.headers_mut();
+    headers.set("Content-Disposition", &content_disposition(&user_data.username))
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    headers.set("Cache-Control", "no-store")
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    Ok(response)
+}
+
+// Audit events of all users, or of the account currently named ?user=
+async fn admin_audit_handler(req: Request, env: Env) -> std::result::Result<Response, Error> {
+    authenticate_admin(&req, &env)?;
+
+    let url = req.url().map_err(|_| Error::InvalidRoute)?;
+    let user = url.query_pairs().find(|(name, _)| name == "user").map(|(_, value)| value.into_owned());
+    let account_id = match user {
+        Some(username) => Some(
+            get_user_from_kv(&env, &username).await
+                .map_err(|_| Error::UserNotFound)?
+                .id()
+                .to_string(),
+        ),
+        None => None,
+    };
+    audit_query_response(&req, &env, account_id.as_deref()).await
+}
+
+// One page of audit events, paged with ?limit= and the returned ?cursor=
+async fn audit_query_response(req: &Request, env: &Env, user_id: Option<&str>) -> std::result::Result<Response, Error> {
+    let url = req.url().map_err(|_| Error::InvalidRoute)?;
+    let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+    let limit = param("limit").and_then(|limit| limit.parse().ok()).unwrap_or(DEFAULT_QUERY_LIMIT);
+
+    let sink = AuditSink::from_env(env)
+        .map_err(|_| Error::KvStore)?;
+    let page = sink.query(user_id, limit, param("cursor")).await
+        .map_err(|_| Error::KvStore)?
+        .ok_or(Error::AuditQueryUnsupported)?;
+
+    Response::from_json(&serde_json::json!({
+        "success": true,
+        "events": page.events,
+        "cursor": page.cursor
+    })).map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+async fn device_code_handler(mut req: Request, env: Env) -> std::result::Result<Response, Error> {
+    let device_req: DeviceCodeRequest = parse_form_or_json(&mut req).await?;
+    let scope = resolve_scopes(device_req.scope.as_deref(), &default_token_scopes(&env))
+        .map_err(Error::InvalidScope)?;
+
+    let expires_in = env_parse::<i64>(&env, "DEVICE_CODE_EXPIRATION_SECONDS", 600);
+    let interval = env_parse::<i64>(&env, "DEVICE_CODE_INTERVAL_SECONDS", 5);
+
+    // Browser page of the frontend where the user logs in and enters the code. The Worker only
+    // has the POST /device/verify API behind it, so there is no default.
+    let verification_uri = env.var("DEVICE_VERIFICATION_URI")
+        .map(|uri| uri.to_string())
+        .map_err(|_| Error::MissingDeviceVerificationUri)?;
+
+    let device_code = generate_device_code();
+    let now = Utc::now().timestamp();
+    let grant = DeviceGrant {
+        user_code: generate_user_code(),
+        status: DeviceGrantStatus::Pending,
+        username: None,
+        created_at: now,
+        expires_at: now + expires_in,
+        interval,
+        last_polled_at: None,
+        scope,
+    };
+
+    store_device_grant(&env, &device_code, &grant).await
+        .map_err(|_| Error::KvStore)?;
+
+    let response = DeviceCodeResponse {
+        verification_uri_complete: format!("{}?user_code={}", verification_uri, grant.user_code),
+        verification_uri,
+        device_code,
+        user_code: grant.user_code,
+        expires_in,
+        interval,
+    };
+
+    Response::from_json(&response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+async fn device_verify_handler(
+    mut req: Request,
+    env: Env,
+    required_scopes: &[&str],
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    // Only a logged-in user can approve a device
+    let (user_data, auth) = authenticate_request(&req, &env, required_scopes).await?;
+    audit.set_user(&user_data);
+
+    // Approving a device hands out a JWT, which a personal access token must not be able to mint
+    if let AuthMethod::AccessToken = auth.method {
+        return Err(Error::AccessTokenNotAllowed);
+    }
+
+    let verify_req: DeviceVerifyRequest = req
+        .json()
+        .await
+        .map_err(|err| Error::DecodeBody(err.to_string()))?;
+
+    let user_code = normalize_user_code(&verify_req.user_code);
+    let device_code = get_device_code_for_user_code(&env, &user_code).await
+        .map_err(|_| Error::InvalidUserCode)?;
+    let mut grant = get_device_grant(&env, &device_code).await
+        .map_err(|_| Error::InvalidUserCode)?;
+
+    if !grant.awaiting_decision(Utc::now().timestamp()) {
+        return Err(Error::InvalidUserCode);
+    }
+
+    let approve = verify_req.approve.unwrap_or(true);
+    if approve {
+        // A device can never end up with more access than the session approving it
+        let grant_scopes: Vec<&str> = grant.scope.split_whitespace().collect();
+        if !has_scopes(&auth.scope, &grant_scopes) {
+            return Err(Error::InsufficientScope(grant.scope.clone()));
+        }
+
+        grant.approve(&user_data.username);
+    } else {
+        grant.deny();
+        audit.set_event_type(AuditEventType::DeviceDeny);
+    }
+
+    store_device_grant(&env, &device_code, &grant).await
+        .map_err(|_| Error::KvStore)?;
+
+    Response::from_json(&serde_json::json!({
+        "success": true,
+        "message": if approve { "Device approved" } else { "Device denied" }
+    })).map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+async fn token_handler(mut req: Request, env: Env, audit: &AuditRecorder) -> std::result::Result<Response, Error> {
+    // RFC 8628 clients send form-encoded bodies, JSON is accepted for consistency with the rest of the API
+    let token_req: TokenRequest = parse_form_or_json(&mut req).await?;
+
+    // OAuth errors are 400 responses rather than errors, record their code as the failure reason
+    let encode = |res: Result<Response>| res.map_err(|err| Error::EncodeBody(err.to_string()));
+    let oauth_error = |code: &str, description: &str| {
+        audit.fail(code);
+        encode(oauth_error_response(code, description))
+    };
+
+    if token_req.grant_type != DEVICE_CODE_GRANT_TYPE {
+        return oauth_error("unsupported_grant_type", "Only the device_code grant is supported");
+    }
+
+    let device_code = match token_req.device_code {
+        Some(device_code) => device_code,
+        None => return oauth_error("invalid_request", "Missing device_code"),
+    };
+
+    // Grants disappear from KV once their TTL passes
+    let mut grant = match get_device_grant(&env, &device_code).await {
+        Ok(grant) => grant,
+        Err(_) => return oauth_error("expired_token", "The device code has expired"),
+    };
+
+    let now = Utc::now().timestamp();
+    if grant.is_expired(now) {
+        return oauth_error("expired_token", "The device code has expired");
+    }
+
+    match grant.status {
+        DeviceGrantStatus::Pending => {
+            // Clients polling faster than the interval must back off (RFC 8628 section 3.5)
+            let too_fast = grant.record_poll(now);
+            store_device_grant(&env, &device_code, &grant).await
+                .map_err(|_| Error::KvStore)?;
+
+            // Polling is not worth an audit event until the grant is decided
+            audit.skip();
+            if too_fast {
+                encode(oauth_error_response("slow_down", "Polling too frequently"))
+            } else {
+                encode(oauth_error_response("authorization_pending", "The user has not approved this device yet"))
+            }
+        }
+        DeviceGrantStatus::Denied => {
+            delete_device_grant(&env, &device_code, &grant.user_code).await
+                .map_err(|_| Error::KvStore)?;
+            oauth_error("access_denied", "The user denied this device")
+        }
+        DeviceGrantStatus::Approved => {
+            // Device codes are single use
+            delete_device_grant(&env, &device_code, &grant.user_code).await
+                .map_err(|_| Error::KvStore)?;
+
+            let username = grant.username.ok_or(Error::UserNotFound)?;
+            audit.set_username(&username);
+            let user_data = get_user_from_kv(&env, &username).await
+                .map_err(|_| Error::UserNotFound)?;
+            audit.set_user(&user_data);
+            if user_data.pending_deletion.is_some() {
+                return Err(Error::AccountPendingDeletion);
+            }
+
+            // Device tokens never count as a fresh password authentication
+            let (token, expires_in) = issue_jwt_token(&env, &user_data, &grant.scope, 0, "").await?;
+
+            let response = TokenResponse {
+                access_token: token,
+                token_type: "Bearer".to_string(),
+                expires_in,
+                scope: grant.scope,
+            };
+
+            Response::from_json(&response)
+                .map_err(|err| Error::EncodeBody(err.to_string()))
+        }
+    }
+}
+
+async fn create_access_token_handler(
+    mut req: Request,
+    env: Env,
+    required_scopes: &[&str],
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    let (mut user_data, auth) = authenticate_request(&req, &env, required_scopes).await?;
+    audit.set_user(&user_data);
+
+    // A leaked access token must not be able to mint more of itself
+    if let AuthMethod::AccessToken = auth.method {
+        return Err(Error::AccessTokenNotAllowed);
+    }
+
+    let create_req: CreateAccessTokenRequest = req
+        .json()
+        .await
+        .map_err(|err| Error::DecodeBody(err.to_string()))?;
+
+    // A new long-lived credential is as sensitive as a password change
+    require_step_up(&env, &user_data, &auth, create_req.current_password.as_deref())?;
+
+    let now = Utc::now().timestamp();
+    let expires_at = create_req.validate(now)
+        .map_err(Error::InvalidAccessTokenRequest)?;
+
+    let scope = resolve_scopes(create_req.scope.as_deref(), &default_token_scopes(&env))
+        .map_err(Error::InvalidScope)?;
+
+    // Access tokens can never carry more than the session creating them
+    let token_scopes: Vec<&str> = scope.split_whitespace().collect();
+    if !has_scopes(&auth.scope, &token_scopes) {
+        return Err(Error::InsufficientScope(scope));
+    }
+
+    if user_data.access_tokens.len() >= MAX_ACCESS_TOKENS_PER_USER {
+        return Err(Error::AccessTokenLimitReached);
+    }
+
+    let (token_id, token) = generate_access_token(&token_lookup_secret(&env)?);
+    let record = PersonalAccessToken {
+        id: token_id,
+        name: create_req.name,
+        token_hash: hash_access_token(&token),
+        scope,
+        created_at: now,
+        expires_at,
+    };
+
+    store_access_token_owner(&env, &record.id, &user_data.username).await
+        .map_err(|_| Error::KvStore)?;
+    add_to_expiry_index(&env, &record).await
+        .map_err(|_| Error::KvStore)?;
+
+    let access_token = AccessTokenSummary::from(&record);
+    user_data.access_tokens.push(record);
+    store_user_in_kv(&env, &user_data.username, &user_data).await
+        .map_err(|_| Error::KvStore)?;
+
+    let response = CreateAccessTokenResponse {
+        success: true,
+        message: "Access token created. Store it now, it will not be shown again.".to_string(),
+        token,
+        access_token,
+    };
+
+    Response::from_json(&response)
+        .map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+async fn list_access_tokens_handler(req: Request, env: Env, required_scopes: &[&str]) -> std::result::Result<Response, Error> {
+    let (user_data, _) = authenticate_request(&req, &env, required_scopes).await?;
+
+    let response = ListAccessTokensResponse {
+        success: true,
+        access_tokens: user_data.access_tokens.iter().map(AccessTokenSummary::from).collect(),
+    };
+
     Response::from_json(&response)
         .map_err(|err| Error::EncodeBody(err.to_string()))
 }
 
+async fn revoke_access_token_handler(
+    req: Request,
+    env: Env,
+    required_scopes: &[&str],
+    token_id: &str,
+    audit: &AuditRecorder,
+) -> std::result::Result<Response, Error> {
+    let (mut user_data, _) = authenticate_request(&req, &env, required_scopes).await?;
+    audit.set_user(&user_data);
+
+    let position = user_data
+        .access_tokens
+        .iter()
+        .position(|access_token| access_token.id == token_id)
+        .ok_or(Error::AccessTokenNotFound)?;
+    let revoked = user_data.access_tokens.remove(position);
+
+    store_user_in_kv(&env, &user_data.username, &user_data).await
+        .map_err(|_| Error::KvStore)?;
+    delete_access_token_entries(&env, &revoked).await
+        .map_err(|_| Error::KvStore)?;
+
+    Response::from_json(&serde_json::json!({
+        "success": true,
+        "message": "Access token revoked"
+    })).map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+// Re-encrypt stored secrets after a master key rotation, one page of KV keys per call
+async fn reencrypt_handler(mut req: Request, env: Env) -> std::result::Result<Response, Error> {
+    authenticate_admin(&req, &env)?;
+
+    let reencrypt_req: ReencryptRequest = parse_form_or_json(&mut req).await?;
+
+    let keyring = MasterKeyring::from_env(&env)
+        .map_err(|err| Error::MasterKey(err.to_string()))?
+        .ok_or_else(|| Error::MasterKey("MASTER_KEY_VERSION is not set".to_string()))?;
+
+    // The signing key set is one record, it is handled with the first page
+    let signing_keys_updated = if reencrypt_req.cursor.is_none() {
+        reencrypt_signing_keys(&env, &keyring).await
+            .map_err(|_| Error::KvStore)?
+    } else {
+        0
+    };
+
+    let (scanned, updated, cursor) = reencrypt_users_page(&env, &keyring, reencrypt_req.cursor, reencrypt_req.limit.unwrap_or(100))
+        .await
+        .map_err(|_| Error::KvStore)?;
+
+    Response::from_json(&serde_json::json!({
+        "success": true,
+        "master_key_version": keyring.current_version,
+        "scanned": scanned,
+        "updated": updated,
+        "signing_keys_updated": signing_keys_updated,
+        "cursor": cursor
+    })).map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+// Rotate the server signing keys (also meant to be called on a schedule)
+async fn rotate_signing_keys_handler(req: Request, env: Env) -> std::result::Result<Response, Error> {
+    authenticate_admin(&req, &env)?;
+
+    let report = rotate_signing_keys(&env).await?;
+
+    Response::from_json(&serde_json::json!({
+        "success": true,
+        "rotation": report
+    })).map_err(|err| Error::EncodeBody(err.to_string()))
+}
+
+// Publish the public signing keys: current, next (so caches pick it up before it goes live)
+// and retired keys that may still have signed unexpired tokens
+async fn jwks_handler(env: Env) -> std::result::Result<Response, Error> {
+    let key_set = get_signing_keys(&env).await
+        .map_err(|_| Error::KvStore)?;
+
+    let mut response = Response::from_json(&key_set.jwks())
+        .map_err(|err| Error::EncodeBody(err.to_string()))?;
+    response.headers_mut().set("Cache-Control", "public, max-age=300").ok();
+    Ok(response)
+}
+
 async fn health_handler() -> std::result::Result<Response, Error> {
     Response::from_json(&serde_json::json!({
         "status": "healthy",
@@ -440,65 +1851,309 @@ enum Error {
     InvalidPasswordHash(String),
     Verify(String),
     MissingTurnstileToken,
-    MissingTurnstileSecret,
-    InvalidTurnstileToken,
+    MissingCaptchaSecret(String),
+    HumanVerification(VerificationError),
     JwtGeneration(String),
     UserNotFound,
-    KvStoreError,
+    KvStore,
     MissingAuthToken,
     InvalidAuthFormat,
     InvalidJwtToken,
     ExpiredJwtToken,
     UsernameExists,
+    InvalidUsername,
+    InvalidUserCode,
+    InvalidScope(String),
+    InsufficientScope(String),
+    InvalidAccessToken,
+    ExpiredAccessToken,
+    AccessTokenNotAllowed,
+    AccessTokenNotFound,
+    AccessTokenLimitReached,
+    InvalidAccessTokenRequest(String), // Which field is out of range
+    InvalidCurrentPassword,
+    StepUpRequired(i64),
+    MissingTokenLookupSecret,
+    MissingDeviceVerificationUri,
+    SigningKeysNotInitialized,
+    InvalidAdminKey,
+    MasterKey(String),
+    CorsOriginNotAllowed,
+    InvalidCsrfToken,
+    ChallengeRequired,
+    CaptchaTokenReused,
+    AuditQueryUnsupported,
+    AccountPendingDeletion,
+    InvalidRestoreToken,
+    AccountNotPendingDeletion,
 }
 
+// Every error is returned as
+// {"success": false, "error": {"code": "<stable code>", "message": "<text>", "request_id": "<id>"}}
+// Codes are part of the API contract, messages are for humans and may change.
 impl Error {
-    fn to_response(&self) -> worker::Result<Response> {
+    fn status(&self) -> u16 {
         match self {
-            Error::InvalidRoute => Response::error("Route not found", 404),
-            Error::DecodeBody(err) => {
-                Response::error(format!("Failed to decode request body: {}", err), 400)
-            }
-            Error::EncodeBody(err) => {
-                Response::error(format!("Failed to encode response body: {}", err), 500)
-            }
-            Error::Hash(err) => Response::error(format!("Failed to hash password: {}", err), 500),
-            Error::InvalidPasswordHash(err) => {
-                Response::error(format!("Invalid password hash: {}", err), 400)
-            }
-            Error::Verify(err) => {
-                Response::error(format!("Failed to verify password: {}", err), 500)
-            }
-            Error::MissingTurnstileToken => {
-                Response::error("Missing Turnstile token in cf-turnstile-response header", 400)
-            }
-            Error::MissingTurnstileSecret => {
-                Response::error("Missing Turnstile secret key in environment", 500)
-            }            Error::InvalidTurnstileToken => {
-                Response::error("Invalid Turnstile token", 401)
-            }
-            Error::JwtGeneration(err) => {
-                Response::error(format!("Failed to generate JWT: {}", err), 500)
-            }Error::UserNotFound => {
-                Response::error("Invalid credentials", 401)
-            }
-            Error::KvStoreError => {
-                Response::error("Internal server error", 500)
-            }
-            Error::MissingAuthToken => {
-                Response::error("Missing Authorization header", 401)
+            Error::InvalidRoute => 404,
+            Error::DecodeBody(_) => 400,
+            Error::EncodeBody(_) => 500,
+            Error::Hash(_) => 500,
+            Error::InvalidPasswordHash(_) => 500, // A corrupt stored hash is our problem, not the client's
+            Error::Verify(_) => 500,
+            Error::MissingTurnstileToken => 400,
+            Error::MissingCaptchaSecret(_) => 500,
+            Error::HumanVerification(VerificationError::InvalidSecret(_)) => 500,
+            Error::HumanVerification(VerificationError::Unavailable(_)) => 502,
+            Error::HumanVerification(_) => 401,
+            Error::JwtGeneration(_) => 500,
+            Error::UserNotFound => 401,
+            Error::KvStore => 500,
+            Error::MissingAuthToken => 401,
+            Error::InvalidAuthFormat => 401,
+            Error::InvalidJwtToken => 401,
+            Error::ExpiredJwtToken => 401,
+            Error::UsernameExists => 409,
+            Error::InvalidUsername => 400,
+            Error::InvalidUserCode => 400,
+            Error::InvalidScope(_) => 400,
+            Error::InsufficientScope(_) => 403,
+            Error::InvalidAccessToken => 401,
+            Error::ExpiredAccessToken => 401,
+            Error::AccessTokenNotAllowed => 403,
+            Error::AccessTokenNotFound => 404,
+            Error::AccessTokenLimitReached => 400,
+            Error::InvalidAccessTokenRequest(_) => 400,
+            Error::InvalidCurrentPassword => 403,
+            Error::StepUpRequired(_) => 401,
+            Error::MissingTokenLookupSecret => 500,
+            Error::MissingDeviceVerificationUri => 500,
+            Error::SigningKeysNotInitialized => 500,
+            Error::InvalidAdminKey => 401,
+            Error::MasterKey(_) => 500,
+            Error::CorsOriginNotAllowed => 403,
+            Error::InvalidCsrfToken => 403,
+            Error::ChallengeRequired => 401,
+            Error::CaptchaTokenReused => 401,
+            Error::AuditQueryUnsupported => 501,
+            Error::AccountPendingDeletion => 403,
+            Error::InvalidRestoreToken => 400,
+            Error::AccountNotPendingDeletion => 409,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidRoute => "not_found",
+            Error::DecodeBody(_) => "invalid_request_body",
+            Error::EncodeBody(_) => "internal_error",
+            Error::Hash(_) => "internal_error",
+            Error::InvalidPasswordHash(_) => "internal_error",
+            Error::Verify(_) => "internal_error",
+            Error::MissingTurnstileToken => "missing_turnstile_token",
+            Error::MissingCaptchaSecret(_) => "server_misconfigured",
+            // Codes keep their Turnstile names whatever the provider, clients depend on them
+            Error::HumanVerification(VerificationError::InvalidSecret(_)) => "server_misconfigured",
+            Error::HumanVerification(VerificationError::Unavailable(_)) => "turnstile_unavailable",
+            Error::HumanVerification(VerificationError::Stale(_)) => "expired_turnstile_token",
+            Error::HumanVerification(_) => "invalid_turnstile_token",
+            Error::JwtGeneration(_) => "internal_error",
+            Error::UserNotFound => "invalid_credentials",
+            Error::KvStore => "internal_error",
+            Error::MissingAuthToken => "missing_token",
+            Error::InvalidAuthFormat => "invalid_auth_format",
+            Error::InvalidJwtToken => "invalid_token",
+            Error::ExpiredJwtToken => "expired_token",
+            Error::UsernameExists => "username_exists",
+            Error::InvalidUsername => "invalid_username",
+            Error::InvalidUserCode => "invalid_user_code",
+            Error::InvalidScope(_) => "invalid_scope",
+            Error::InsufficientScope(_) => "insufficient_scope",
+            Error::InvalidAccessToken => "invalid_token",
+            Error::ExpiredAccessToken => "expired_token",
+            Error::AccessTokenNotAllowed => "access_token_not_allowed",
+            Error::AccessTokenNotFound => "access_token_not_found",
+            Error::AccessTokenLimitReached => "access_token_limit_reached",
+            Error::InvalidAccessTokenRequest(_) => "invalid_access_token_request",
+            Error::InvalidCurrentPassword => "invalid_current_password",
+            Error::StepUpRequired(_) => "step_up_required",
+            Error::MissingTokenLookupSecret => "server_misconfigured",
+            Error::MissingDeviceVerificationUri => "server_misconfigured",
+            Error::SigningKeysNotInitialized => "server_misconfigured",
+            Error::InvalidAdminKey => "invalid_admin_key",
+            Error::MasterKey(_) => "server_misconfigured",
+            Error::CorsOriginNotAllowed => "origin_not_allowed",
+            Error::InvalidCsrfToken => "invalid_csrf_token",
+            Error::ChallengeRequired => "challenge_required",
+            Error::CaptchaTokenReused => "turnstile_token_reused",
+            Error::AuditQueryUnsupported => "audit_query_unsupported",
+            Error::AccountPendingDeletion => "account_pending_deletion",
+            Error::InvalidRestoreToken => "invalid_restore_token",
+            Error::AccountNotPendingDeletion => "account_not_pending_deletion",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Error::InvalidRoute => "Route not found".to_string(),
+            Error::DecodeBody(_) => "Request body is malformed or missing required fields".to_string(),
+            Error::EncodeBody(_) => "Internal server error".to_string(),
+            Error::Hash(_) => "Internal server error".to_string(),
+            Error::InvalidPasswordHash(_) => "Internal server error".to_string(),
+            Error::Verify(_) => "Internal server error".to_string(),
+            Error::MissingTurnstileToken => "Missing CAPTCHA token in cf-turnstile-response header".to_string(),
+            Error::MissingCaptchaSecret(_) => "Server is not configured correctly".to_string(),
+            Error::HumanVerification(VerificationError::Rejected(codes)) => {
+                // siteverify's error codes are documented and safe to pass on, e.g. timeout-or-duplicate
+                format!("Invalid CAPTCHA token: {}", codes.join(", "))
             }
-            Error::InvalidAuthFormat => {
-                Response::error("Invalid Authorization format. Use 'Bearer <token>'", 401)
-            }            Error::InvalidJwtToken => {
-                Response::error("Invalid JWT token", 401)
+            Error::HumanVerification(VerificationError::InvalidSecret(_)) => "Server is not configured correctly".to_string(),
+            Error::HumanVerification(VerificationError::Unavailable(_)) => "Bot verification is temporarily unavailable".to_string(),
+            Error::HumanVerification(VerificationError::Stale(_)) => "CAPTCHA challenge has expired, solve it again".to_string(),
+            Error::HumanVerification(VerificationError::LowScore(_)) => "Verification score too low, try again".to_string(),
+            Error::HumanVerification(_) => "Invalid CAPTCHA token".to_string(),
+            Error::JwtGeneration(_) => "Internal server error".to_string(),
+            Error::UserNotFound => "Invalid credentials".to_string(),
+            Error::KvStore => "Internal server error".to_string(),
+            Error::MissingAuthToken => "Missing Authorization header".to_string(),
+            Error::InvalidAuthFormat => "Invalid Authorization format. Use 'Bearer <token>'".to_string(),
+            Error::InvalidJwtToken => "Invalid JWT token".to_string(),
+            Error::ExpiredJwtToken => "JWT token has expired".to_string(),
+            Error::UsernameExists => "Username already exists".to_string(),
+            Error::InvalidUsername => "Username must not be empty or contain ':'".to_string(),
+            Error::InvalidUserCode => "Invalid or expired user code".to_string(),
+            Error::InvalidScope(scope) => format!("Unknown scope: {}", scope),
+            Error::InsufficientScope(required) => format!("This operation requires the scopes: {}", required),
+            Error::InvalidAccessToken => "Invalid access token".to_string(),
+            Error::ExpiredAccessToken => "Access token has expired".to_string(),
+            Error::AccessTokenNotAllowed => "Personal access tokens cannot be used for this operation".to_string(),
+            Error::AccessTokenNotFound => "Access token not found".to_string(),
+            Error::AccessTokenLimitReached => "Access token limit reached, revoke an unused token first".to_string(),
+            Error::InvalidAccessTokenRequest(reason) => format!("Invalid access token request: {}", reason),
+            Error::InvalidCurrentPassword => "Current password is incorrect".to_string(),
+            Error::StepUpRequired(_) => "Recent authentication required, provide current_password or log in again".to_string(),
+            Error::MissingTokenLookupSecret => "Server is not configured correctly".to_string(),
+            Error::MissingDeviceVerificationUri => "Server is not configured correctly".to_string(),
+            Error::SigningKeysNotInitialized => "Server is not configured correctly".to_string(),
+            Error::InvalidAdminKey => "Invalid admin key".to_string(),
+            Error::MasterKey(_) => "Server is not configured correctly".to_string(),
+            Error::CorsOriginNotAllowed => "Origin is not allowed".to_string(),
+            Error::InvalidCsrfToken => "Missing or invalid X-CSRF-Token header".to_string(),
+            Error::ChallengeRequired => "Solve the CAPTCHA challenge and retry with its token".to_string(),
+            Error::CaptchaTokenReused => "CAPTCHA token was already used, solve a new challenge".to_string(),
+            Error::AuditQueryUnsupported => "The configured audit sink cannot be queried".to_string(),
+            Error::AccountPendingDeletion => "Account is scheduled for deletion, restore it to use it again".to_string(),
+            Error::InvalidRestoreToken => "Invalid or expired restore token".to_string(),
+            Error::AccountNotPendingDeletion => "Account is not scheduled for deletion".to_string(),
+        }
+    }
+
+    // What went wrong internally, only ever written to the logs
+    fn detail(&self) -> Option<String> {
+        match self {
+            Error::DecodeBody(err) => Some(format!("failed to decode request body: {}", err)),
+            Error::EncodeBody(err) => Some(format!("failed to encode response body: {}", err)),
+            Error::Hash(err) => Some(format!("failed to hash password: {}", err)),
+            Error::InvalidPasswordHash(err) => Some(format!("stored password hash is invalid: {}", err)),
+            Error::Verify(err) => Some(format!("failed to verify password: {}", err)),
+            Error::JwtGeneration(err) => Some(format!("failed to generate JWT: {}", err)),
+            Error::MasterKey(err) => Some(format!("master key configuration error: {}", err)),
+            Error::MissingCaptchaSecret(name) => Some(format!("{} is not set", name)),
+            Error::HumanVerification(err) => Some(err.to_string()),
+            Error::MissingTokenLookupSecret => Some("TOKEN_LOOKUP_SECRET is not set".to_string()),
+            Error::MissingDeviceVerificationUri => Some("DEVICE_VERIFICATION_URI is not set".to_string()),
+            Error::SigningKeysNotInitialized => Some("no signing keys yet, rotate them once to create the key set".to_string()),
+            Error::KvStore => Some("KV storage operation failed".to_string()),
+            _ => None,
+        }
+    }
+
+    // Log server-side details under the request id the client receives. Plain client errors
+    // without details are only logged at debug level.
+    fn log(&self, request_id: &str) {
+        let level = match self.detail() {
+            Some(_) if self.status() >= 500 => logging::Level::Error,
+            Some(_) => logging::Level::Warn,
+            None => logging::Level::Debug,
+        };
+        logging::log(level, "request failed", serde_json::json!({
+            "request_id": request_id,
+            "status": self.status(),
+            "code": self.code(),
+            "detail": self.detail()
+        }));
+    }
+
+    fn to_response(&self, request_id: &str) -> worker::Result<Response> {
+        let mut response = Response::from_json(&serde_json::json!({
+            "success": false,
+            "error": {
+                "code": self.code(),
+                "message": self.message(),
+                "request_id": request_id
             }
-            Error::ExpiredJwtToken => {
-                Response::error("JWT token has expired", 401)
+        }))?
+        .with_status(self.status());
+
+        match self {
+            Error::InsufficientScope(required) => {
+                // RFC 6750 section 3.1: tell the client which scopes the route needs
+                response.headers_mut().set(
+                    "WWW-Authenticate",
+                    &format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", required),
+                )?;
             }
-            Error::UsernameExists => {
-                Response::error("Username already exists", 409)
+            Error::StepUpRequired(max_age) => {
+                // RFC 9470: ask the client to re-authenticate (or send current_password)
+                response.headers_mut().set(
+                    "WWW-Authenticate",
+                    &format!("Bearer error=\"insufficient_user_authentication\", max_age={}", max_age),
+                )?;
             }
+            _ => {}
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_params_are_percent_decoded() {
+        assert_eq!(path_param("/admin/users/alice/restore", "/admin/users/", "/restore").as_deref(), Some("alice"));
+        assert_eq!(path_param("/admin/users/j%C3%B6rg%20m/restore", "/admin/users/", "/restore").as_deref(), Some("jörg m"));
+        assert_eq!(path_param("/admin/users/a%2Fb/restore", "/admin/users/", "/restore").as_deref(), Some("a/b"));
+    }
+
+    #[test]
+    fn path_params_span_exactly_one_segment() {
+        assert_eq!(path_param("/admin/users/a/b/restore", "/admin/users/", "/restore"), None);
+        assert_eq!(path_param("/admin/users/restore", "/admin/users/", "/restore"), None);
+        assert_eq!(path_param("/admin/users/alice/restore/restore", "/admin/users/", "/restore"), None);
+    }
+
+    #[test]
+    fn malformed_percent_escapes_are_refused() {
+        for path in ["/admin/users/%/restore", "/admin/users/%4/restore", "/admin/users/%+f/restore", "/admin/users/%ff/restore"] {
+            assert_eq!(path_param(path, "/admin/users/", "/restore"), None, "{}", path);
         }
     }
+
+    #[test]
+    fn access_token_validation_errors_reach_the_client() {
+        let err = Error::InvalidAccessTokenRequest("expires_in_days must be between 1 and 3650".to_string());
+        assert_eq!(err.status(), 400);
+        assert_eq!(err.code(), "invalid_access_token_request");
+        assert!(err.message().ends_with("expires_in_days must be between 1 and 3650"));
+    }
+
+    #[test]
+    fn empty_usernames_are_invalid() {
+        let username = path_param("/admin/users//restore", "/admin/users/", "/restore").unwrap();
+        assert!(matches!(validate_username(&username), Err(Error::InvalidUsername)));
+        assert!(matches!(validate_username("a:b"), Err(Error::InvalidUsername)));
+        assert!(validate_username("alice").is_ok());
+    }
 }
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+use chrono::Utc;
+use crate::client::{client_ip, client_location, client_user_agent};
+
+// The most recent password checks of each user, successful or not, so users can spot sign-ins
+// that were not theirs. Kept as one capped list per user under "login_history:<username>".
+
+const LOGIN_HISTORY_PREFIX: &str = "login_history:";
+const MAX_LOGIN_HISTORY_ENTRIES: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoginRecord {
+    pub timestamp: i64,
+    pub success: bool,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl LoginRecord {
+    pub fn from_request(req: &Request, success: bool) -> Self {
+        let location = client_location(req);
+        LoginRecord {
+            timestamp: Utc::now().timestamp(),
+            success,
+            ip: client_ip(req),
+            country: location.country,
+            region: location.region,
+            city: location.city,
+            user_agent: client_user_agent(req),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LoginHistoryResponse {
+    pub success: bool,
+    pub logins: Vec<LoginRecord>,
+}
+
+fn history_key(username: &str) -> String {
+    format!("{}{}", LOGIN_HISTORY_PREFIX, username)
+}
+
+// Newest first
+pub async fn get_login_history(env: &Env, username: &str) -> std::result::Result<Vec<LoginRecord>, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    match kv.get(&history_key(username)).text().await? {
+        Some(history_json) => Ok(serde_json::from_str(&history_json)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Newest first, the oldest entries fall off once the list is full
+fn push_login_record(history: &mut Vec<LoginRecord>, record: LoginRecord) {
+    history.insert(0, record);
+    history.truncate(MAX_LOGIN_HISTORY_ENTRIES);
+}
+
+// Read-modify-write, so concurrent logins can drop each other's entry. Good enough for a list
+// people skim, the audit log is the authoritative record.
+pub async fn record_login_attempt(env: &Env, username: &str, record: LoginRecord) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    let mut history = get_login_history(env, username).await?;
+    push_login_record(&mut history, record);
+    kv.put(&history_key(username), serde_json::to_string(&history)?)?.execute().await?;
+    Ok(())
+}
+
+// The history follows the account when it is renamed
+pub async fn rename_login_history(env: &Env, old_username: &str, new_username: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    if let Some(history_json) = kv.get(&history_key(old_username)).text().await? {
+        kv.put(&history_key(new_username), history_json)?.execute().await?;
+        kv.delete(&history_key(old_username)).await?;
+    }
+    Ok(())
+}
+
+pub async fn delete_login_history(env: &Env, username: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    kv.delete(&history_key(username)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64) -> LoginRecord {
+        LoginRecord {
+            timestamp,
+            success: true,
+            ip: None,
+            country: None,
+            region: None,
+            city: None,
+            user_agent: None,
+        }
+    }
+
+    fn timestamps(history: &[LoginRecord]) -> Vec<i64> {
+        history.iter().map(|record| record.timestamp).collect()
+    }
+
+    #[test]
+    fn newest_login_comes_first() {
+        let mut history = Vec::new();
+        for timestamp in 1..=3 {
+            push_login_record(&mut history, record(timestamp));
+        }
+        assert_eq!(timestamps(&history), [3, 2, 1]);
+    }
+
+    #[test]
+    fn history_keeps_the_latest_twenty_logins() {
+        let mut history = Vec::new();
+        for timestamp in 1..=25 {
+            push_login_record(&mut history, record(timestamp));
+        }
+        assert_eq!(history.len(), MAX_LOGIN_HISTORY_ENTRIES);
+        assert_eq!(timestamps(&history), (6..=25).rev().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use worker::*;
+use crate::kv_store::{find_user_in_kv, store_user_in_kv};
+use crate::access_tokens::{delete_access_token_owner, find_access_token_owner, expired_access_tokens, remove_from_expiry_index};
+use crate::account_deletion::{purge_user, due_deletions, remove_from_purge_index};
+use crate::logging;
+
+// Maintenance jobs run by the scheduled (cron) handler. CRON_JOBS lists the enabled jobs, nothing
+// runs by default. A job runs on every cron trigger unless CRON_SCHEDULE_<JOB> names the one
+// trigger it belongs to, e.g. CRON_SCHEDULE_ROTATE_SIGNING_KEYS = "0 3 * * 1" with that
+// expression also listed under [triggers] in wrangler.toml.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MaintenanceJob {
+    RotateSigningKeys,    // Promote the next signing key and prune expired ones
+    PruneAccessTokens,    // Drop expired personal access tokens and their lookup entries
+    PurgeDeletedAccounts, // Hard-delete accounts whose deletion grace period has ended
+}
+
+const ALL_JOBS: [MaintenanceJob; 3] = [
+    MaintenanceJob::RotateSigningKeys,
+    MaintenanceJob::PruneAccessTokens,
+    MaintenanceJob::PurgeDeletedAccounts,
+];
+
+// Index entries handled per run, each costs a handful of KV operations. Anything left stays due
+// for the next run.
+const PRUNE_BATCH_SIZE: u64 = 50;
+const PURGE_BATCH_SIZE: u64 = 50;
+
+impl MaintenanceJob {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaintenanceJob::RotateSigningKeys => "rotate_signing_keys",
+            MaintenanceJob::PruneAccessTokens => "prune_access_tokens",
+            MaintenanceJob::PurgeDeletedAccounts => "purge_deleted_accounts",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        ALL_JOBS.into_iter().find(|job| job.name() == name)
+    }
+}
+
+// The enabled jobs that belong to this cron trigger, in CRON_JOBS order
+pub fn jobs_for_cron(env: &Env, cron: &str) -> Vec<MaintenanceJob> {
+    let enabled = env.var("CRON_JOBS").map(|v| v.to_string()).unwrap_or_default();
+
+    let (jobs, unknown) = parse_cron_jobs(&enabled);
+    for name in unknown {
+        logging::warn("CRON_JOBS names an unknown job", serde_json::json!({ "job": name }));
+    }
+
+    jobs.into_iter()
+        .filter(|job| {
+            let schedule = env.var(&schedule_var(*job)).map(|v| v.to_string()).ok();
+            runs_on_cron(schedule.as_deref(), cron)
+        })
+        .collect()
+}
+
+// The jobs CRON_JOBS lists, in order, and the names in it that are no job
+fn parse_cron_jobs(enabled: &str) -> (Vec<MaintenanceJob>, Vec<&str>) {
+    let mut jobs = Vec::new();
+    let mut unknown = Vec::new();
+    for name in enabled.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match MaintenanceJob::from_name(name) {
+            Some(job) => jobs.push(job),
+            None => unknown.push(name),
+        }
+    }
+    (jobs, unknown)
+}
+
+fn schedule_var(job: MaintenanceJob) -> String {
+    format!("CRON_SCHEDULE_{}", job.name().to_ascii_uppercase())
+}
+
+// A job without a CRON_SCHEDULE_<JOB> runs on every trigger
+fn runs_on_cron(schedule: Option<&str>, cron: &str) -> bool {
+    schedule.is_none_or(|schedule| schedule.trim() == cron.trim())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Ok,
+    Skipped,
+    Failed,
+}
+
+// What a job did, logged as one JSON line per job and run
+#[derive(Serialize)]
+pub struct JobReport {
+    pub job: &'static str,
+    pub cron: String,
+    pub status: JobStatus,
+    pub details: serde_json::Value,
+    pub duration_ms: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct PruneReport {
+    pub entries_due: usize,
+    pub tokens_removed: usize,
+}
+
+// Remove personal access tokens that expired before `now`, at most PRUNE_BATCH_SIZE
+pub async fn prune_expired_access_tokens(env: &Env, now: i64) -> std::result::Result<PruneReport, Box<dyn std::error::Error>> {
+    let mut report = PruneReport::default();
+
+    for (token_id, expires_at) in expired_access_tokens(env, now, PRUNE_BATCH_SIZE).await? {
+        report.entries_due += 1;
+
+        // Revoked tokens and tokens of purged accounts have no owner left, only the entry goes
+        if let Some(owner) = find_access_token_owner(env, &token_id).await? {
+            if let Some(mut user_data) = find_user_in_kv(env, &owner).await? {
+                let token_count = user_data.access_tokens.len();
+                user_data.access_tokens.retain(|token| token.id != token_id);
+                if user_data.access_tokens.len() < token_count {
+                    store_user_in_kv(env, &owner, &user_data).await?;
+                    report.tokens_removed += 1;
+                }
+            }
+            delete_access_token_owner(env, &token_id).await?;
+        }
+        remove_from_expiry_index(env, &token_id, expires_at).await?;
+    }
+
+    Ok(report)
+}
+
+#[derive(Serialize, Default)]
+pub struct PurgeReport {
+    pub entries_due: usize,
+    pub accounts_purged: usize,
+    #[serde(skip)]
+    pub purged_usernames: Vec<String>, // For the user.deleted webhooks, kept out of the logs
+}
+
+// Hard-delete the accounts whose pending deletion is due at `now`, at most PURGE_BATCH_SIZE
+pub async fn purge_deleted_accounts(env: &Env, now: i64) -> std::result::Result<PurgeReport, Box<dyn std::error::Error>> {
+    let mut report = PurgeReport::default();
+
+    for (username, purge_at) in due_deletions(env, now, PURGE_BATCH_SIZE).await? {
+        report.entries_due += 1;
+
+        // Entries of restored accounts, or of ones deleted again later, are only dropped
+        let user_data = find_user_in_kv(env, &username).await?;
+        if let Some(user_data) = user_data.filter(|user_data| {
+            user_data.pending_deletion.as_ref().is_some_and(|pending| pending.purge_at == purge_at)
+        }) {
+            purge_user(env, &user_data).await?;
+            report.accounts_purged += 1;
+            report.purged_usernames.push(user_data.username);
+        }
+        remove_from_purge_index(env, &username, purge_at).await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cron_jobs_are_read_in_order() {
+        let (jobs, unknown) = parse_cron_jobs(" purge_deleted_accounts, rotate_signing_keys,,prune_access_tokens ");
+        assert_eq!(
+            jobs,
+            [MaintenanceJob::PurgeDeletedAccounts, MaintenanceJob::RotateSigningKeys, MaintenanceJob::PruneAccessTokens]
+        );
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn unknown_cron_jobs_are_reported() {
+        let (jobs, unknown) = parse_cron_jobs("rotate_signing_keys,vacuum,Rotate_Signing_Keys");
+        assert_eq!(jobs, [MaintenanceJob::RotateSigningKeys]);
+        assert_eq!(unknown, ["vacuum", "Rotate_Signing_Keys"]);
+    }
+
+    #[test]
+    fn no_cron_jobs_by_default() {
+        assert_eq!(parse_cron_jobs(""), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn schedule_variables_are_named_after_the_job() {
+        assert_eq!(schedule_var(MaintenanceJob::RotateSigningKeys), "CRON_SCHEDULE_ROTATE_SIGNING_KEYS");
+        assert_eq!(schedule_var(MaintenanceJob::PurgeDeletedAccounts), "CRON_SCHEDULE_PURGE_DELETED_ACCOUNTS");
+    }
+
+    #[test]
+    fn scheduled_jobs_only_run_on_their_trigger() {
+        assert!(runs_on_cron(None, "*/15 * * * *"));
+        assert!(runs_on_cron(Some(" 0 3 * * 1 "), "0 3 * * 1"));
+        assert!(!runs_on_cron(Some("0 3 * * 1"), "*/15 * * * *"));
+    }
+}
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use worker::*;
+use worker::js_sys::{Function, JSON, Reflect};
+use worker::wasm_bindgen::{JsCast, JsValue};
+use crate::logging;
+
+// Metrics written to a Workers Analytics Engine dataset bound as METRICS. Every data point has
+// the metric name as its index and first blob, up to four labels and one or two numbers:
+//
+//     metric           blob2       blob3      blob4        blob5       double1       double2
+//     request          method      route      status       error code  duration_ms   status
+//     login            outcome     reason                              1
+//     captcha          provider    action     outcome                  1
+//     password_verify  outcome                                         duration_ms
+//     kv               operation   outcome                             duration_ms
+//
+// Without the binding nothing is recorded, unless METRICS_SINK = "log" asks for each data point
+// as a "metric" log line (local development).
+
+const METRICS_BINDING: &str = "METRICS";
+
+#[derive(Serialize)]
+pub struct DataPoint {
+    pub indexes: Vec<String>,
+    pub blobs: Vec<String>,
+    pub doubles: Vec<f64>,
+}
+
+impl DataPoint {
+    fn new(metric: &str, labels: &[&str], doubles: &[f64]) -> Self {
+        DataPoint {
+            indexes: vec![metric.to_string()],
+            blobs: std::iter::once(metric).chain(labels.iter().copied()).map(str::to_string).collect(),
+            doubles: doubles.to_vec(),
+        }
+    }
+}
+
+pub enum Metrics {
+    AnalyticsEngine(JsValue),
+    Log,
+    None,
+}
+
+impl Metrics {
+    pub fn from_env(env: &Env) -> Self {
+        let dataset = Reflect::get(env, &JsValue::from_str(METRICS_BINDING))
+            .ok()
+            .filter(|dataset| !dataset.is_undefined() && !dataset.is_null());
+        if let Some(dataset) = dataset {
+            return Metrics::AnalyticsEngine(dataset);
+        }
+
+        match env.var("METRICS_SINK").map(|v| v.to_string()).unwrap_or_default().as_str() {
+            "log" => Metrics::Log,
+            _ => Metrics::None,
+        }
+    }
+
+    // Never fails the caller, a point that cannot be written is logged and dropped
+    fn write(&self, point: DataPoint) {
+        let written = match self {
+            Metrics::AnalyticsEngine(dataset) => write_data_point(dataset, &point),
+            Metrics::Log => {
+                logging::info("metric", serde_json::to_value(&point).unwrap_or_default());
+                Ok(())
+            }
+            Metrics::None => Ok(()),
+        };
+        if let Err(err) = written {
+            logging::warn("metric not written", serde_json::json!({ "error": err }));
+        }
+    }
+
+    pub fn request(&self, method: &str, route: &str, status: u16, error_code: Option<&str>, duration_ms: i64) {
+        self.write(DataPoint::new(
+            "request",
+            &[method, route, &status.to_string(), error_code.unwrap_or("")],
+            &[duration_ms as f64, status as f64],
+        ));
+    }
+
+    // `reason` is the error code of a failed login
+    pub fn login(&self, success: bool, reason: Option<&str>) {
+        let outcome = if success { "success" } else { "failure" };
+        self.write(DataPoint::new("login", &[outcome, reason.unwrap_or("")], &[1.0]));
+    }
+
+    // `outcome` is "ok" or the error code the CAPTCHA check failed with
+    pub fn captcha(&self, provider: &str, action: &str, outcome: &str) {
+        self.write(DataPoint::new("captcha", &[provider, action, outcome], &[1.0]));
+    }
+
+    // `outcome` is "match", "mismatch" or "error"
+    pub fn password_verify(&self, outcome: &str, duration_ms: i64) {
+        self.write(DataPoint::new("password_verify", &[outcome], &[duration_ms as f64]));
+    }
+
+    pub fn kv(&self, operation: &str, success: bool, duration_ms: i64) {
+        let outcome = if success { "ok" } else { "error" };
+        self.write(DataPoint::new("kv", &[operation, outcome], &[duration_ms as f64]));
+    }
+}
+
+// dataset.writeDataPoint({indexes, blobs, doubles}), which the runtime sends in the background
+fn write_data_point(dataset: &JsValue, point: &DataPoint) -> std::result::Result<(), String> {
+    let point_json = serde_json::to_string(point).map_err(|err| err.to_string())?;
+    let point = JSON::parse(&point_json).map_err(|err| format!("{:?}", err))?;
+    let write = Reflect::get(dataset, &JsValue::from_str("writeDataPoint"))
+        .ok()
+        .and_then(|write| write.dyn_into::<Function>().ok())
+        .ok_or("METRICS is not an Analytics Engine dataset")?;
+    write.call1(dataset, &point).map_err(|err| format!("{:?}", err))?;
+    Ok(())
+}
+
+// Route label for the request metric, with path parameters replaced so usernames and token ids
+// do not end up in the dataset
+pub fn route_template(path: &str) -> String {
+    if path.starts_with("/tokens/") {
+        "/tokens/{id}".to_string()
+    } else if path.starts_with("/admin/users/") && path.ends_with("/restore") {
+        "/admin/users/{username}/restore".to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_ids_are_collapsed() {
+        assert_eq!(route_template("/tokens/3f9a0c1d2e4b5a69"), "/tokens/{id}");
+        assert_eq!(route_template("/tokens/"), "/tokens/{id}");
+    }
+
+    #[test]
+    fn usernames_are_collapsed() {
+        assert_eq!(route_template("/admin/users/alice/restore"), "/admin/users/{username}/restore");
+        assert_eq!(route_template("/admin/users/j%C3%B6rg/restore"), "/admin/users/{username}/restore");
+    }
+
+    #[test]
+    fn fixed_routes_keep_their_path() {
+        for path in ["/login", "/me/audit", "/tokens", "/admin/audit", "/.well-known/jwks.json"] {
+            assert_eq!(route_template(path), path);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use serde::Serialize;
+use crate::human_verifier::{HumanVerifier, VerificationError, VerifiedChallenge, VerifyOptions, post_siteverify, check_siteverify_response};
+
+const SITEVERIFY_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+
+// Google's suggested starting point, 1.0 is very likely a human and 0.0 very likely a bot
+const DEFAULT_MIN_SCORE: f64 = 0.5;
+
+#[derive(Serialize)]
+struct RecaptchaVerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<&'a str>,
+}
+
+// reCAPTCHA v3 never shows a challenge, it scores the visitor instead
+pub struct RecaptchaVerifier {
+    secret: String,
+    min_score: f64,
+    verify_url: String,
+}
+
+impl RecaptchaVerifier {
+    pub fn new(secret: String, min_score: Option<f64>, verify_url: Option<String>) -> Self {
+        RecaptchaVerifier {
+            secret,
+            min_score: min_score.unwrap_or(DEFAULT_MIN_SCORE),
+            verify_url: verify_url.unwrap_or_else(|| SITEVERIFY_URL.to_string()),
+        }
+    }
+}
+
+impl HumanVerifier for RecaptchaVerifier {
+    async fn verify(&self, token: &str, options: &VerifyOptions) -> std::result::Result<VerifiedChallenge, VerificationError> {
+        let body = serde_urlencoded::to_string(RecaptchaVerifyRequest {
+            secret: &self.secret,
+            response: token,
+            remoteip: options.remote_ip.as_deref(),
+        })
+        .map_err(|err| VerificationError::Unavailable(err.to_string()))?;
+
+        let response = post_siteverify(&self.verify_url, &body)
+            .await
+            .map_err(|err| VerificationError::Unavailable(err.to_string()))?;
+
+        let challenge = check_siteverify_response(&response, options, true)?;
+
+        // A v2 token or a missing score is treated as the lowest score
+        let score = response.score.unwrap_or(0.0);
+        if score < self.min_score {
+            return Err(VerificationError::LowScore(score));
+        }
+
+        Ok(challenge)
+    }
+}
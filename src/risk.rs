@@ -0,0 +1,246 @@
+use worker::*;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use crate::token_lookup::{lookup_tag, verify_lookup_tag, to_hex};
+use crate::config::{env_list, env_parse, MIN_KV_TTL_SECONDS};
+use crate::client::{client_ip, js_property};
+
+// Risk-based challenges, enabled with RISK_BASED_CHALLENGE = "true". Instead of demanding a
+// CAPTCHA on every /login and /register, each request is scored from a few signals and only
+// challenged once the score reaches RISK_CHALLENGE_THRESHOLD:
+//
+//     recent attempts from the IP      +10 each (max 40)   failed logins and registrations
+//     recent failures for the user     +15 each (max 60)   failed logins
+//     low bot score                    +50                 cf.botManagement.score below RISK_MIN_BOT_SCORE
+//     high risk country                +30                 RISK_HIGH_RISK_COUNTRIES
+//     high risk network                +30                 RISK_HIGH_RISK_ASNS
+//     unknown device                   +20                 login only, see device tokens below
+//     new IP for the user              +20                 login only
+//
+// A signed device token is handed out after each successful login (cookie and response body,
+// native clients send it back in X-Device-Token). Counters live in KV with a TTL and are only
+// eventually consistent, which is fine for scoring. IPs are hashed before they become KV keys.
+
+const IP_ATTEMPTS_PREFIX: &str = "risk_ip:";
+const USER_FAILURES_PREFIX: &str = "risk_user:";
+const KNOWN_IP_PREFIX: &str = "risk_known_ip:";
+
+pub const DEVICE_COOKIE: &str = "__Host-device";
+pub const DEVICE_HEADER: &str = "X-Device-Token";
+const DEVICE_LOOKUP_CONTEXT: &str = "device";
+
+const DEFAULT_THRESHOLD: i64 = 50;
+const DEFAULT_FAILURE_WINDOW_SECONDS: u64 = 900;
+const DEFAULT_MIN_BOT_SCORE: u32 = 30;
+const KNOWN_IP_TTL_SECONDS: u64 = 90 * 24 * 3600;
+const DEVICE_TOKEN_MAX_AGE_SECONDS: u64 = 365 * 24 * 3600;
+
+pub struct RiskConfig {
+    pub enabled: bool,
+    threshold: i64,
+    failure_window_seconds: u64,
+    min_bot_score: u32,
+    high_risk_countries: Vec<String>,
+    high_risk_asns: Vec<u32>,
+}
+
+// Country codes and ASNs are compared upper case
+fn env_codes(env: &Env, name: &str) -> Vec<String> {
+    env_list(env, name).iter().map(|item| item.to_ascii_uppercase()).collect()
+}
+
+impl RiskConfig {
+    pub fn from_env(env: &Env) -> Self {
+        RiskConfig {
+            enabled: env_parse(env, "RISK_BASED_CHALLENGE", false),
+            threshold: env_parse(env, "RISK_CHALLENGE_THRESHOLD", DEFAULT_THRESHOLD),
+            failure_window_seconds: env_parse(env, "RISK_FAILURE_WINDOW_SECONDS", DEFAULT_FAILURE_WINDOW_SECONDS)
+                .max(MIN_KV_TTL_SECONDS),
+            min_bot_score: env_parse(env, "RISK_MIN_BOT_SCORE", DEFAULT_MIN_BOT_SCORE),
+            high_risk_countries: env_codes(env, "RISK_HIGH_RISK_COUNTRIES"),
+            high_risk_asns: env_codes(env, "RISK_HIGH_RISK_ASNS")
+                .iter()
+                .filter_map(|asn| asn.trim_start_matches("AS").parse().ok())
+                .collect(),
+        }
+    }
+}
+
+pub struct RiskAssessment {
+    pub score: i64,
+    pub reasons: Vec<&'static str>,
+    pub challenge_required: bool,
+}
+
+// request.cf, read defensively: fields are missing in local development and bot management
+// is only present on plans that include it
+struct CfSignals {
+    asn: Option<u32>,
+    country: Option<String>,
+    bot_score: Option<u32>,
+}
+
+fn cf_signals(req: &Request) -> CfSignals {
+    let cf = js_property(req.inner(), "cf");
+    let cf_number = |name: &str| cf.as_ref().and_then(|cf| js_property(cf, name)).and_then(|value| value.as_f64());
+
+    CfSignals {
+        asn: cf_number("asn").map(|asn| asn as u32),
+        country: cf
+            .as_ref()
+            .and_then(|cf| js_property(cf, "country"))
+            .and_then(|country| country.as_string()),
+        bot_score: cf
+            .as_ref()
+            .and_then(|cf| js_property(cf, "botManagement"))
+            .and_then(|bot_management| js_property(&bot_management, "score"))
+            .and_then(|score| score.as_f64())
+            .map(|score| score as u32),
+    }
+}
+
+fn hash_key(parts: &[&str]) -> String {
+    to_hex(&Sha256::digest(parts.join("\n").as_bytes()))
+}
+
+async fn read_counter(kv: &kv::KvStore, key: &str) -> u32 {
+    kv.get(key)
+        .text()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0)
+}
+
+async fn increment_counter(kv: &kv::KvStore, key: &str, ttl: u64) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let count = read_counter(kv, key).await + 1;
+    kv.put(key, count.to_string())?.expiration_ttl(ttl).execute().await?;
+    Ok(())
+}
+
+// Device tokens are "<random id>.<HMAC tag of id and username>", only valid for that user
+pub fn generate_device_token(secret: &[u8], username: &str) -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let id = to_hex(&id);
+    let tag = lookup_tag(secret, DEVICE_LOOKUP_CONTEXT, &format!("{}:{}", id, username));
+    format!("{}.{}", id, tag)
+}
+
+pub fn is_known_device(secret: &[u8], device_token: &str, username: &str) -> bool {
+    device_token
+        .split_once('.')
+        .is_some_and(|(id, tag)| verify_lookup_tag(secret, DEVICE_LOOKUP_CONTEXT, &format!("{}:{}", id, username), tag))
+}
+
+pub fn device_token_from_request(req: &Request) -> Option<String> {
+    req.headers()
+        .get(DEVICE_HEADER)
+        .ok()
+        .flatten()
+        .or_else(|| crate::session::get_cookie(req, DEVICE_COOKIE))
+}
+
+pub fn device_cookie(device_token: &str) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        DEVICE_COOKIE, device_token, DEVICE_TOKEN_MAX_AGE_SECONDS
+    )
+}
+
+// Score a login (username given) or registration (None)
+pub async fn assess(
+    req: &Request,
+    env: &Env,
+    config: &RiskConfig,
+    device_secret: &[u8],
+    username: Option<&str>,
+) -> std::result::Result<RiskAssessment, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    let ip = client_ip(req);
+    let mut score = 0;
+    let mut reasons = Vec::new();
+
+    if let Some(ip) = &ip {
+        let attempts = read_counter(&kv, &format!("{}{}", IP_ATTEMPTS_PREFIX, hash_key(&[ip]))).await;
+        if attempts > 0 {
+            score += (attempts as i64 * 10).min(40);
+            reasons.push("ip_attempts");
+        }
+    }
+
+    let cf = cf_signals(req);
+    if cf.bot_score.is_some_and(|bot_score| bot_score < config.min_bot_score) {
+        score += 50;
+        reasons.push("bot_score");
+    }
+    if cf.country.is_some_and(|country| config.high_risk_countries.contains(&country.to_ascii_uppercase())) {
+        score += 30;
+        reasons.push("country");
+    }
+    if cf.asn.is_some_and(|asn| config.high_risk_asns.contains(&asn)) {
+        score += 30;
+        reasons.push("asn");
+    }
+
+    if let Some(username) = username {
+        let failures = read_counter(&kv, &format!("{}{}", USER_FAILURES_PREFIX, hash_key(&[username]))).await;
+        if failures > 0 {
+            score += (failures as i64 * 15).min(60);
+            reasons.push("user_failures");
+        }
+
+        let known_device = device_token_from_request(req)
+            .is_some_and(|device_token| is_known_device(device_secret, &device_token, username));
+        if !known_device {
+            score += 20;
+            reasons.push("unknown_device");
+        }
+
+        if let Some(ip) = &ip {
+            let known_ip_key = format!("{}{}", KNOWN_IP_PREFIX, hash_key(&[username, ip]));
+            if kv.get(&known_ip_key).text().await?.is_none() {
+                score += 20;
+                reasons.push("new_ip");
+            }
+        }
+    }
+
+    Ok(RiskAssessment {
+        score,
+        reasons,
+        challenge_required: score >= config.threshold,
+    })
+}
+
+// Failed login: counts against both the IP and the username
+pub async fn record_login_failure(req: &Request, env: &Env, config: &RiskConfig, username: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    if let Some(ip) = client_ip(req) {
+        increment_counter(&kv, &format!("{}{}", IP_ATTEMPTS_PREFIX, hash_key(&[&ip])), config.failure_window_seconds).await?;
+    }
+    increment_counter(&kv, &format!("{}{}", USER_FAILURES_PREFIX, hash_key(&[username])), config.failure_window_seconds).await
+}
+
+// Successful login: forget the user's failures and remember the IP
+pub async fn record_login_success(req: &Request, env: &Env, username: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    kv.delete(&format!("{}{}", USER_FAILURES_PREFIX, hash_key(&[username]))).await?;
+    if let Some(ip) = client_ip(req) {
+        kv.put(&format!("{}{}", KNOWN_IP_PREFIX, hash_key(&[username, &ip])), "1")?
+            .expiration_ttl(KNOWN_IP_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+// Registrations count as IP attempts so account farming from one address gets challenged
+pub async fn record_registration(req: &Request, env: &Env, config: &RiskConfig) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    if let Some(ip) = client_ip(req) {
+        increment_counter(&kv, &format!("{}{}", IP_ATTEMPTS_PREFIX, hash_key(&[&ip])), config.failure_window_seconds).await?;
+    }
+    Ok(())
+}
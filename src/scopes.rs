@@ -0,0 +1,61 @@
+// Scopes carried in the `scope` claim of access tokens (space separated, as in OAuth 2.0)
+pub const PROFILE_READ: &str = "profile:read";
+pub const PROFILE_WRITE: &str = "profile:write";
+pub const ACCOUNT_DELETE: &str = "account:delete";
+
+pub const ALL_SCOPES: &[&str] = &[PROFILE_READ, PROFILE_WRITE, ACCOUNT_DELETE];
+
+// Issued when a client does not ask for anything specific, deletion must be requested explicitly
+pub const DEFAULT_SCOPES: &str = "profile:read profile:write";
+
+// Check that every required scope is present in a space separated scope string
+pub fn has_scopes(granted: &str, required: &[&str]) -> bool {
+    required
+        .iter()
+        .all(|scope| granted.split_whitespace().any(|granted| granted == *scope))
+}
+
+// Validate a requested scope string, returning it normalized or the first unknown scope
+pub fn resolve_scopes(requested: Option<&str>, default: &str) -> std::result::Result<String, String> {
+    let requested = requested.unwrap_or(default);
+    let mut scopes: Vec<&str> = Vec::new();
+
+    for scope in requested.split_whitespace() {
+        if !ALL_SCOPES.contains(&scope) {
+            return Err(scope.to_string());
+        }
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    Ok(scopes.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_required_scope_must_be_granted() {
+        assert!(has_scopes("profile:read profile:write", &[PROFILE_READ]));
+        assert!(has_scopes("profile:read  profile:write", &[PROFILE_WRITE, PROFILE_READ]));
+        assert!(has_scopes("profile:read", &[]));
+        assert!(!has_scopes("profile:read", &[PROFILE_READ, ACCOUNT_DELETE]));
+        assert!(!has_scopes("profile:readwrite", &[PROFILE_READ]));
+        assert!(!has_scopes("", &[PROFILE_READ]));
+    }
+
+    #[test]
+    fn requested_scopes_are_normalized() {
+        assert_eq!(resolve_scopes(None, DEFAULT_SCOPES).unwrap(), DEFAULT_SCOPES);
+        assert_eq!(resolve_scopes(Some(" account:delete  profile:read account:delete "), DEFAULT_SCOPES).unwrap(), "account:delete profile:read");
+        assert_eq!(resolve_scopes(Some(""), DEFAULT_SCOPES).unwrap(), "");
+    }
+
+    #[test]
+    fn unknown_scope_is_named() {
+        assert_eq!(resolve_scopes(Some("profile:read admin"), DEFAULT_SCOPES), Err("admin".to_string()));
+        assert_eq!(resolve_scopes(Some("PROFILE:READ"), DEFAULT_SCOPES), Err("PROFILE:READ".to_string()));
+    }
+}
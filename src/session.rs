@@ -0,0 +1,150 @@
+use worker::*;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use subtle::ConstantTimeEq;
+
+// Cookie sessions, enabled with SESSION_MODE = "cookie". The JWT lives in an HttpOnly cookie
+// that page scripts cannot read. Every cookie session carries a random CSRF token, which is
+// signed into the JWT (`csrf` claim) and handed to the client in the login response and a
+// readable cookie. State-changing requests must echo it in the X-CSRF-Token header. A
+// cross-site page can make the browser send the cookie but can never learn the token.
+
+// The __Host- prefix makes browsers insist on Secure, Path=/ and no Domain attribute,
+// so the cookies cannot be set or overwritten by a sibling subdomain
+pub const SESSION_COOKIE: &str = "__Host-session";
+pub const CSRF_COOKIE: &str = "__Host-csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+pub fn session_cookies_enabled(env: &Env) -> bool {
+    env.var("SESSION_MODE")
+        .map(|mode| mode.to_string() == "cookie")
+        .unwrap_or(false)
+}
+
+// SESSION_COOKIE_SAMESITE: Strict (default), Lax, or None for frontends on another site
+fn same_site(env: &Env) -> &'static str {
+    parse_same_site(env.var("SESSION_COOKIE_SAMESITE").map(|v| v.to_string()).ok().as_deref())
+}
+
+fn parse_same_site(value: Option<&str>) -> &'static str {
+    match value.map(str::to_ascii_lowercase).as_deref() {
+        Some("lax") => "Lax",
+        Some("none") => "None",
+        _ => "Strict",
+    }
+}
+
+// Set-Cookie value with the attributes the __Host- prefix demands
+fn host_cookie(name: &str, value: &str, max_age: i64, http_only: bool, same_site: &str) -> String {
+    let http_only = if http_only { "; HttpOnly" } else { "" };
+    format!("{}={}; Path=/; Max-Age={}{}; Secure; SameSite={}", name, value, max_age, http_only, same_site)
+}
+
+pub fn generate_csrf_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Read a cookie from the request's Cookie header
+pub fn get_cookie(req: &Request, name: &str) -> Option<String> {
+    let cookies = req.headers().get("Cookie").ok()??;
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// The session cookie plus the script-readable CSRF cookie, both expiring with the token
+pub fn set_session_cookies(env: &Env, headers: &mut Headers, token: &str, csrf_token: &str, max_age: i64) -> Result<()> {
+    let same_site = same_site(env);
+    headers.append("Set-Cookie", &host_cookie(SESSION_COOKIE, token, max_age, true, same_site))?;
+    headers.append("Set-Cookie", &host_cookie(CSRF_COOKIE, csrf_token, max_age, false, same_site))?;
+    Ok(())
+}
+
+pub fn clear_session_cookies(env: &Env, headers: &mut Headers) -> Result<()> {
+    let same_site = same_site(env);
+    for (name, http_only) in [(SESSION_COOKIE, true), (CSRF_COOKIE, false)] {
+        headers.append("Set-Cookie", &host_cookie(name, "", 0, http_only, same_site))?;
+    }
+    Ok(())
+}
+
+// Safe methods never change state, everything else needs the CSRF header
+pub fn requires_csrf_check(method: &Method) -> bool {
+    !matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+pub fn verify_csrf_token(expected: &str, presented: &str) -> bool {
+    !expected.is_empty() && expected.as_bytes().ct_eq(presented.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(cookie: &str) -> Vec<String> {
+        cookie.split(';').skip(1).map(|attribute| attribute.trim().to_ascii_lowercase()).collect()
+    }
+
+    #[test]
+    fn cookies_satisfy_the_host_prefix() {
+        for (name, http_only) in [(SESSION_COOKIE, true), (CSRF_COOKIE, false)] {
+            assert!(name.starts_with("__Host-"));
+            let cookie = host_cookie(name, "value", 900, http_only, "Strict");
+            assert!(cookie.starts_with(&format!("{}=value;", name)));
+
+            let attributes = attributes(&cookie);
+            assert!(attributes.contains(&"secure".to_string()));
+            assert!(attributes.contains(&"path=/".to_string()));
+            assert!(attributes.contains(&"max-age=900".to_string()));
+            assert!(attributes.contains(&"samesite=strict".to_string()));
+            assert!(!attributes.iter().any(|attribute| attribute.starts_with("domain")));
+        }
+    }
+
+    #[test]
+    fn only_the_session_cookie_is_hidden_from_scripts() {
+        assert!(attributes(&host_cookie(SESSION_COOKIE, "jwt", 900, true, "Strict")).contains(&"httponly".to_string()));
+        assert!(!attributes(&host_cookie(CSRF_COOKIE, "csrf", 900, false, "Strict")).contains(&"httponly".to_string()));
+    }
+
+    #[test]
+    fn cleared_cookies_expire_immediately() {
+        let cookie = host_cookie(SESSION_COOKIE, "", 0, true, "Lax");
+        assert!(cookie.starts_with("__Host-session=;"));
+        assert!(attributes(&cookie).contains(&"max-age=0".to_string()));
+    }
+
+    #[test]
+    fn same_site_defaults_to_strict() {
+        assert_eq!(parse_same_site(None), "Strict");
+        assert_eq!(parse_same_site(Some("LAX")), "Lax");
+        assert_eq!(parse_same_site(Some("none")), "None");
+        assert_eq!(parse_same_site(Some("relaxed")), "Strict");
+    }
+
+    #[test]
+    fn safe_methods_are_exempt_from_the_csrf_check() {
+        for method in [Method::Get, Method::Head, Method::Options] {
+            assert!(!requires_csrf_check(&method));
+        }
+        for method in [Method::Post, Method::Put, Method::Patch, Method::Delete] {
+            assert!(requires_csrf_check(&method));
+        }
+    }
+
+    #[test]
+    fn csrf_token_must_match_exactly() {
+        let token = generate_csrf_token();
+        assert!(verify_csrf_token(&token, &token));
+        assert!(!verify_csrf_token(&token, ""));
+        assert!(!verify_csrf_token(&token, &token[1..]));
+        assert!(!verify_csrf_token(&token, &generate_csrf_token()));
+        // A session without a CSRF token cannot be matched by an empty header
+        assert!(!verify_csrf_token("", ""));
+    }
+}
@@ -0,0 +1,341 @@
+use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
+use worker::*;
+use chrono::Utc;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine as _, engine::general_purpose};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use crate::encryption::{MasterKeyring, encrypt_secret, decrypt_secret, needs_reencryption, rewrap_secret};
+use crate::token_lookup::to_hex;
+
+// Server-managed Ed25519 signing keys, used when JWT_SIGNING_MODE = "server".
+// The key set always holds one `current` key (signs new tokens) and one `next` key (already
+// published in the JWKS so verifiers can cache it before it goes live). On rotation current
+// becomes `retired`, next becomes current and a fresh next is generated. Retired keys still
+// verify tokens until every token they signed has expired, then they are pruned.
+
+// Internal KV keys contain ':' which usernames cannot, so they never collide with user records
+const SIGNING_KEYS_KEY: &str = "config:signing_keys";
+
+// Private keys are encrypted under the master key when one is configured
+const SIGNING_KEY_CONTEXT: &str = "signing_key";
+
+// Key ids are 8 random bytes in hex
+const KID_LENGTH: usize = 16;
+
+// How long an isolate verifies tokens against the key set it last read. A token naming an
+// unknown kid only triggers a KV read once this has passed, so forged tokens cannot force one
+// per request. New keys are published as `next` long before they sign anything.
+const KEY_SET_CACHE_SECONDS: i64 = 60;
+
+thread_local! {
+    static CACHED_KEY_SET: RefCell<Option<(i64, SigningKeySet)>> = const { RefCell::new(None) };
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningKeyStatus {
+    Next,
+    Current,
+    Retired,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SigningKey {
+    pub kid: String,
+    pub status: SigningKeyStatus,
+    pub created_at: i64,
+    pub activated_at: Option<i64>,
+    pub retired_at: Option<i64>,
+    pub private_key: String, // PKCS#8 document, base64 (or an encryption envelope)
+    pub public_key: String,  // Raw Ed25519 public key, base64url as in the JWK `x` member
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct SigningKeySet {
+    pub keys: Vec<SigningKey>,
+}
+
+// Summary returned by the rotation endpoint and job
+#[derive(Serialize)]
+pub struct RotationReport {
+    pub current_kid: String,
+    pub next_kid: String,
+    pub retired_kids: Vec<String>,
+    pub pruned_kids: Vec<String>,
+}
+
+fn generate_signing_key(keyring: Option<&MasterKeyring>, status: SigningKeyStatus, now: i64) -> std::result::Result<SigningKey, Box<dyn std::error::Error>> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| "Failed to generate signing key")?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|_| "Failed to load generated signing key")?;
+
+    let mut kid = [0u8; 8];
+    OsRng.fill_bytes(&mut kid);
+
+    let kid = to_hex(&kid);
+    let private_key = general_purpose::STANDARD.encode(pkcs8.as_ref());
+    let private_key = match keyring {
+        Some(keyring) => encrypt_secret(keyring, SIGNING_KEY_CONTEXT, &kid, &private_key)?,
+        None => private_key,
+    };
+
+    Ok(SigningKey {
+        kid,
+        status,
+        created_at: now,
+        activated_at: if status == SigningKeyStatus::Current { Some(now) } else { None },
+        retired_at: None,
+        private_key,
+        public_key: general_purpose::URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+    })
+}
+
+impl SigningKeySet {
+    pub fn current(&self) -> Option<&SigningKey> {
+        self.keys.iter().find(|key| key.status == SigningKeyStatus::Current)
+    }
+
+    // Any key of the set, including next: a location still reading the key set from before a
+    // rotation (KV is eventually consistent for up to 60s) sees the new current key as next
+    pub fn verification_key(&self, kid: &str) -> Option<&SigningKey> {
+        self.keys.iter().find(|key| key.kid == kid)
+    }
+
+    // Make sure there is a current and a next key, returns true when the set changed
+    pub fn ensure_initialized(&mut self, keyring: Option<&MasterKeyring>, now: i64) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+        let mut changed = false;
+        if self.current().is_none() {
+            self.keys.push(generate_signing_key(keyring, SigningKeyStatus::Current, now)?);
+            changed = true;
+        }
+        if !self.keys.iter().any(|key| key.status == SigningKeyStatus::Next) {
+            self.keys.push(generate_signing_key(keyring, SigningKeyStatus::Next, now)?);
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    // Promote next to current, retire current, publish a fresh next key and prune retired keys
+    // whose tokens can no longer be valid (retired for longer than `max_token_lifetime` seconds).
+    // The first rotation only creates the current and next keys.
+    pub fn rotate(&mut self, keyring: Option<&MasterKeyring>, now: i64, max_token_lifetime: i64) -> std::result::Result<RotationReport, Box<dyn std::error::Error>> {
+        let first_rotation = self.current().is_none();
+        self.ensure_initialized(keyring, now)?;
+
+        let mut retired_kids = Vec::new();
+        if !first_rotation {
+            self.promote_next(now, &mut retired_kids);
+            self.keys.push(generate_signing_key(keyring, SigningKeyStatus::Next, now)?);
+        }
+
+        let pruned_kids = self.prune(now, max_token_lifetime);
+
+        let kid_with = |status| {
+            self.keys
+                .iter()
+                .find(|key| key.status == status)
+                .map(|key| key.kid.clone())
+                .unwrap_or_default()
+        };
+
+        Ok(RotationReport {
+            current_kid: kid_with(SigningKeyStatus::Current),
+            next_kid: kid_with(SigningKeyStatus::Next),
+            retired_kids,
+            pruned_kids,
+        })
+    }
+
+    fn promote_next(&mut self, now: i64, retired_kids: &mut Vec<String>) {
+        for key in self.keys.iter_mut() {
+            match key.status {
+                SigningKeyStatus::Current => {
+                    key.status = SigningKeyStatus::Retired;
+                    key.retired_at = Some(now);
+                    retired_kids.push(key.kid.clone());
+                }
+                SigningKeyStatus::Next => {
+                    key.status = SigningKeyStatus::Current;
+                    key.activated_at = Some(now);
+                }
+                SigningKeyStatus::Retired => {}
+            }
+        }
+    }
+
+    // Drop retired keys once every token they signed has expired
+    pub fn prune(&mut self, now: i64, max_token_lifetime: i64) -> Vec<String> {
+        let mut pruned = Vec::new();
+        self.keys.retain(|key| {
+            let expired = key.status == SigningKeyStatus::Retired
+                && key.retired_at.is_some_and(|retired_at| retired_at + max_token_lifetime < now);
+            if expired {
+                pruned.push(key.kid.clone());
+            }
+            !expired
+        });
+        pruned
+    }
+
+    // Bring every private key up to the current master key, returns how many changed
+    pub fn reencrypt(&mut self, keyring: &MasterKeyring) -> std::result::Result<usize, Box<dyn std::error::Error>> {
+        let mut updated = 0;
+        for key in self.keys.iter_mut().filter(|key| needs_reencryption(keyring, &key.private_key)) {
+            key.private_key = rewrap_secret(keyring, SIGNING_KEY_CONTEXT, &key.kid, &key.private_key)?;
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    // Public keys in JWKS format (RFC 7517 / RFC 8037)
+    pub fn jwks(&self) -> serde_json::Value {
+        let keys: Vec<serde_json::Value> = self
+            .keys
+            .iter()
+            .map(|key| {
+                serde_json::json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "alg": "EdDSA",
+                    "use": "sig",
+                    "kid": key.kid,
+                    "x": key.public_key
+                })
+            })
+            .collect();
+        serde_json::json!({ "keys": keys })
+    }
+}
+
+// PKCS#8 document of a signing key, decrypted if needed
+pub fn signing_key_der(keyring: Option<&MasterKeyring>, key: &SigningKey) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let private_key = decrypt_secret(keyring, SIGNING_KEY_CONTEXT, &key.kid, &key.private_key)?;
+    Ok(general_purpose::STANDARD.decode(private_key)?)
+}
+
+pub async fn get_signing_keys(env: &Env) -> std::result::Result<SigningKeySet, Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+
+    match kv.get(SIGNING_KEYS_KEY).text().await? {
+        Some(key_set_json) => Ok(serde_json::from_str(&key_set_json)?),
+        None => Ok(SigningKeySet::default()),
+    }
+}
+
+pub async fn store_signing_keys(env: &Env, key_set: &SigningKeySet) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let kv = env.kv("USERS_KV")?;
+    let key_set_json = serde_json::to_string(key_set)?;
+
+    kv.put(SIGNING_KEYS_KEY, key_set_json)?.execute().await?;
+    cache_key_set(Utc::now().timestamp(), key_set);
+    Ok(())
+}
+
+fn is_valid_kid(kid: &str) -> bool {
+    kid.len() == KID_LENGTH && kid.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn cache_key_set(now: i64, key_set: &SigningKeySet) {
+    CACHED_KEY_SET.with(|cached| *cached.borrow_mut() = Some((now, key_set.clone())));
+}
+
+fn cached_key_set(now: i64) -> Option<SigningKeySet> {
+    CACHED_KEY_SET.with(|cached| {
+        cached
+            .borrow()
+            .as_ref()
+            .filter(|(read_at, _)| now - read_at < KEY_SET_CACHE_SECONDS)
+            .map(|(_, key_set)| key_set.clone())
+    })
+}
+
+// The key a JWT header names, from this isolate's copy of the key set while it is fresh.
+// Malformed kids are rejected without touching KV.
+pub async fn find_verification_key(env: &Env, kid: &str) -> std::result::Result<Option<SigningKey>, Box<dyn std::error::Error>> {
+    if !is_valid_kid(kid) {
+        return Ok(None);
+    }
+
+    let now = Utc::now().timestamp();
+    let key_set = match cached_key_set(now) {
+        Some(key_set) => key_set,
+        None => {
+            let key_set = get_signing_keys(env).await?;
+            // Not before the first rotation, its keys should verify as soon as they are stored
+            if !key_set.keys.is_empty() {
+                cache_key_set(now, &key_set);
+            }
+            key_set
+        }
+    };
+    Ok(key_set.verification_key(kid).cloned())
+}
+
+// Re-encrypt the stored key set under the current master key, returns how many keys changed
+pub async fn reencrypt_signing_keys(env: &Env, keyring: &MasterKeyring) -> std::result::Result<usize, Box<dyn std::error::Error>> {
+    let mut key_set = get_signing_keys(env).await?;
+
+    let updated = key_set.reencrypt(keyring)?;
+    if updated > 0 {
+        store_signing_keys(env, &key_set).await?;
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_kids_pass_the_format_check() {
+        let mut key_set = SigningKeySet::default();
+        key_set.ensure_initialized(None, 0).unwrap();
+
+        assert!(key_set.keys.iter().all(|key| is_valid_kid(&key.kid)));
+        assert!(!is_valid_kid("../../config"));
+        assert!(!is_valid_kid("0123456789ABCDEF"));
+        assert!(!is_valid_kid(""));
+    }
+
+    #[test]
+    fn first_rotation_creates_the_key_set() {
+        let mut key_set = SigningKeySet::default();
+        let report = key_set.rotate(None, 0, 3600).unwrap();
+
+        assert!(report.retired_kids.is_empty());
+        assert_eq!(key_set.current().unwrap().kid, report.current_kid);
+        assert_eq!(key_set.keys.len(), 2);
+    }
+
+    #[test]
+    fn reencrypt_moves_private_keys_to_the_current_master_key() {
+        let old = MasterKeyring::from_keys(1, vec![(1, [1u8; 32])]);
+        let new = MasterKeyring::from_keys(2, vec![(1, [1u8; 32]), (2, [2u8; 32])]);
+
+        let mut key_set = SigningKeySet::default();
+        key_set.ensure_initialized(Some(&old), 0).unwrap();
+        let der = signing_key_der(Some(&old), key_set.current().unwrap()).unwrap();
+
+        assert_eq!(key_set.reencrypt(&new).unwrap(), 2);
+        assert_eq!(key_set.reencrypt(&new).unwrap(), 0);
+        assert_eq!(signing_key_der(Some(&new), key_set.current().unwrap()).unwrap(), der);
+    }
+
+    #[test]
+    fn key_promoted_elsewhere_still_verifies_against_a_stale_key_set() {
+        let mut stale = SigningKeySet::default();
+        stale.ensure_initialized(None, 0).unwrap();
+        let next_kid = stale.keys.iter().find(|key| key.status == SigningKeyStatus::Next).unwrap().kid.clone();
+
+        let mut rotated = SigningKeySet { keys: stale.keys.clone() };
+        let report = rotated.rotate(None, 60, 3600).unwrap();
+
+        assert_eq!(report.current_kid, next_kid);
+        assert!(stale.verification_key(&report.current_kid).is_some());
+        assert!(rotated.verification_key(&report.retired_kids[0]).is_some());
+        assert!(stale.verification_key("unknown").is_none());
+    }
+}
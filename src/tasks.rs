@@ -0,0 +1,337 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use serde::{Deserialize, Serialize};
+use worker::*;
+use crate::audit::{AuditEvent, AuditSink};
+use crate::webhooks::{WebhookConfig, WebhookEvent, deliver, send_once};
+use crate::logging;
+use crate::config::env_parse;
+
+// Side effects that should not add latency to a request (webhook deliveries, audit writes) are
+// typed tasks. With a task queue they are enqueued and run by this Worker's queue consumer,
+// without one they run after the response with ctx.wait_until. TASK_QUEUE picks the queue:
+//
+//     (unset)   the TASKS_QUEUE producer binding if there is one, otherwise no queue
+//     memory    per-isolate in-memory queue, drained after each response (local development, tests)
+//     none      no queue even if the binding exists
+//
+// Failed attempts are re-enqueued with exponential backoff (10s, 20s, 40s, ...) until
+// TASK_MAX_ATTEMPTS, then the message goes to the TASKS_DEAD_LETTER_QUEUE binding, or to the
+// logs when there is none. Failures that cannot succeed on retry are dead-lettered immediately.
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const INITIAL_RETRY_DELAY_SECONDS: u32 = 10;
+// Queues accept delays of up to 12 hours
+const MAX_RETRY_DELAY_SECONDS: u32 = 12 * 3600;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Task {
+    DeliverWebhook { url: String, event: WebhookEvent },
+    WriteAuditEvent { event: AuditEvent },
+}
+
+impl Task {
+    fn name(&self) -> &'static str {
+        match self {
+            Task::DeliverWebhook { .. } => "deliver_webhook",
+            Task::WriteAuditEvent { .. } => "write_audit_event",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TaskMessage {
+    pub task: Task,
+    #[serde(default)]
+    pub attempt: u32, // Attempts made so far
+}
+
+impl TaskMessage {
+    pub fn new(task: Task) -> Self {
+        TaskMessage { task, attempt: 0 }
+    }
+}
+
+// Result of one attempt
+enum Attempt {
+    Done,
+    Retry(String),
+    Fail(String),
+}
+
+// What happens to a message after an attempt
+#[derive(Debug, PartialEq)]
+enum NextStep {
+    Finish,
+    Retry { error: String, delay_seconds: u32 },
+    DeadLetter(String),
+}
+
+// Keeps the invocation alive until a future completes, for both fetch and scheduled events
+pub trait WaitUntil {
+    fn keep_alive(&self, future: impl Future<Output = ()> + 'static);
+}
+
+impl WaitUntil for Context {
+    fn keep_alive(&self, future: impl Future<Output = ()> + 'static) {
+        self.wait_until(future);
+    }
+}
+
+impl WaitUntil for ScheduleContext {
+    fn keep_alive(&self, future: impl Future<Output = ()> + 'static) {
+        self.wait_until(future);
+    }
+}
+
+thread_local! {
+    static MEMORY_QUEUE: RefCell<VecDeque<TaskMessage>> = const { RefCell::new(VecDeque::new()) };
+}
+
+pub enum TaskQueue {
+    Cloudflare(Queue),
+    Memory,
+}
+
+impl TaskQueue {
+    // None when tasks should run in the request's own invocation
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let mode = env.var("TASK_QUEUE").map(|v| v.to_string()).unwrap_or_default();
+        match mode.as_str() {
+            "memory" => Some(TaskQueue::Memory),
+            "none" => None,
+            _ => env.queue("TASKS_QUEUE").ok().map(TaskQueue::Cloudflare),
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        matches!(self, TaskQueue::Memory)
+    }
+
+    pub async fn enqueue(&self, message: &TaskMessage, delay_seconds: u32) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        match self {
+            TaskQueue::Cloudflare(queue) => {
+                queue.send(MessageBuilder::new(message).delay_seconds(delay_seconds).build()).await?;
+            }
+            // Delays are ignored, retries run in the same drain
+            TaskQueue::Memory => MEMORY_QUEUE.with(|queue| queue.borrow_mut().push_back(message.clone())),
+        }
+        Ok(())
+    }
+}
+
+fn max_attempts(env: &Env) -> u32 {
+    env_parse(env, "TASK_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS).max(1)
+}
+
+fn retry_delay_seconds(attempt: u32) -> u32 {
+    INITIAL_RETRY_DELAY_SECONDS
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY_SECONDS)
+}
+
+// `attempt` counts the attempt just made, starting at 1
+fn next_step(result: Attempt, attempt: u32, max_attempts: u32) -> NextStep {
+    match result {
+        Attempt::Done => NextStep::Finish,
+        Attempt::Fail(err) => NextStep::DeadLetter(err),
+        Attempt::Retry(err) if attempt >= max_attempts => NextStep::DeadLetter(err),
+        Attempt::Retry(error) => NextStep::Retry { error, delay_seconds: retry_delay_seconds(attempt) },
+    }
+}
+
+// Run a task once
+async fn attempt(env: &Env, task: &Task) -> Attempt {
+    match task {
+        Task::DeliverWebhook { url, event } => {
+            let config = match WebhookConfig::from_env(env) {
+                Ok(Some(config)) => config,
+                Ok(None) => return Attempt::Fail("webhooks are no longer configured".to_string()),
+                Err(err) => return Attempt::Retry(err.to_string()),
+            };
+            let body = match serde_json::to_string(event) {
+                Ok(body) => body,
+                Err(err) => return Attempt::Fail(err.to_string()),
+            };
+            match send_once(&config, url, event, &body).await {
+                Ok(true) => Attempt::Done,
+                Ok(false) => Attempt::Fail(format!("refused by {}", url)),
+                Err(err) => Attempt::Retry(err),
+            }
+        }
+        Task::WriteAuditEvent { event } => {
+            let written = match AuditSink::from_env(env) {
+                Ok(sink) => sink.write(event).await,
+                Err(err) => Err(err),
+            };
+            match written {
+                Ok(()) => Attempt::Done,
+                Err(err) => Attempt::Retry(err.to_string()),
+            }
+        }
+    }
+}
+
+async fn dead_letter(env: &Env, message: &TaskMessage, reason: &str) {
+    logging::error("task dead-lettered", serde_json::json!({
+        "task": message.task.name(),
+        "attempt": message.attempt,
+        "error": reason
+    }));
+
+    let sent = match env.queue("TASKS_DEAD_LETTER_QUEUE") {
+        Ok(queue) => queue.send(message).await.map_err(|err| err.to_string()),
+        Err(_) => Err("TASKS_DEAD_LETTER_QUEUE is not bound".to_string()),
+    };
+    if let Err(err) = sent {
+        // Keep the message in the logs so it can still be replayed by hand
+        logging::error("dead letter not queued", serde_json::json!({
+            "error": err,
+            "task_message": message
+        }));
+    }
+}
+
+// Run one queued message, scheduling a retry or dead-lettering it on failure. An error means the
+// retry could not be enqueued, the caller should then let the queue redeliver the message.
+pub async fn process(env: &Env, queue: &TaskQueue, message: &TaskMessage) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut message = message.clone();
+    message.attempt += 1;
+
+    let result = attempt(env, &message.task).await;
+    match next_step(result, message.attempt, max_attempts(env)) {
+        NextStep::Finish => {}
+        NextStep::DeadLetter(err) => dead_letter(env, &message, &err).await,
+        NextStep::Retry { error, delay_seconds } => {
+            logging::warn("task failed, retrying", serde_json::json!({
+                "task": message.task.name(),
+                "attempt": message.attempt,
+                "error": error
+            }));
+            queue.enqueue(&message, delay_seconds).await?;
+        }
+    }
+    Ok(())
+}
+
+// Drain the in-memory queue, retries included
+pub async fn drain_memory_queue(env: Env) {
+    while let Some(message) = MEMORY_QUEUE.with(|queue| queue.borrow_mut().pop_front()) {
+        if let Err(err) = process(&env, &TaskQueue::Memory, &message).await {
+            logging::error("task could not be retried", serde_json::json!({
+                "task": message.task.name(),
+                "error": err.to_string()
+            }));
+        }
+    }
+}
+
+// Without a queue: run the task after the response, with in-process retries where they are cheap
+pub async fn run_inline(env: Env, task: Task) {
+    match task {
+        Task::DeliverWebhook { url, event } => match WebhookConfig::from_env(&env) {
+            Ok(Some(config)) => deliver(config, url, event).await,
+            Ok(None) => {}
+            Err(err) => logging::error("webhook not sent", serde_json::json!({
+                "event_id": event.id,
+                "error": err.to_string()
+            })),
+        },
+        Task::WriteAuditEvent { event } => {
+            let written = match AuditSink::from_env(&env) {
+                Ok(sink) => sink.write(&event).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = written {
+                logging::error("failed to write audit event", serde_json::json!({
+                    "request_id": event.request_id,
+                    "error": err.to_string()
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    // The memory queue never waits on anything, one poll finishes it
+    fn run<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("memory queue operation did not complete"),
+        }
+    }
+
+    fn webhook_message(event_type: &str) -> TaskMessage {
+        TaskMessage::new(Task::DeliverWebhook {
+            url: "https://hooks.example.com/auth".to_string(),
+            event: WebhookEvent::new(event_type, serde_json::json!({})),
+        })
+    }
+
+    fn queued_event_types() -> Vec<(String, u32)> {
+        MEMORY_QUEUE.with(|queue| {
+            queue
+                .borrow_mut()
+                .drain(..)
+                .map(|message| match message.task {
+                    Task::DeliverWebhook { event, .. } => (event.event_type, message.attempt),
+                    Task::WriteAuditEvent { .. } => panic!("unexpected audit task"),
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn memory_queue_keeps_messages_in_order_and_ignores_delays() {
+        let queue = TaskQueue::Memory;
+        assert!(queue.is_memory());
+        run(queue.enqueue(&webhook_message("user.registered"), 0)).unwrap();
+        let mut retried = webhook_message("user.deleted");
+        retried.attempt = 2;
+        run(queue.enqueue(&retried, 3600)).unwrap();
+
+        assert_eq!(
+            queued_event_types(),
+            [("user.registered".to_string(), 0), ("user.deleted".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_queue_limit() {
+        assert_eq!(retry_delay_seconds(1), 10);
+        assert_eq!(retry_delay_seconds(2), 20);
+        assert_eq!(retry_delay_seconds(3), 40);
+        assert_eq!(retry_delay_seconds(13), 40960);
+        assert_eq!(retry_delay_seconds(14), MAX_RETRY_DELAY_SECONDS);
+        assert_eq!(retry_delay_seconds(u32::MAX), MAX_RETRY_DELAY_SECONDS);
+    }
+
+    #[test]
+    fn transient_failures_are_retried_until_the_last_attempt() {
+        assert_eq!(next_step(Attempt::Done, 1, 5), NextStep::Finish);
+        assert_eq!(
+            next_step(Attempt::Retry("timeout".to_string()), 1, 5),
+            NextStep::Retry { error: "timeout".to_string(), delay_seconds: 10 }
+        );
+        assert_eq!(
+            next_step(Attempt::Retry("timeout".to_string()), 4, 5),
+            NextStep::Retry { error: "timeout".to_string(), delay_seconds: 80 }
+        );
+        assert_eq!(next_step(Attempt::Retry("timeout".to_string()), 5, 5), NextStep::DeadLetter("timeout".to_string()));
+    }
+
+    #[test]
+    fn permanent_failures_are_dead_lettered_immediately() {
+        assert_eq!(
+            next_step(Attempt::Fail("refused".to_string()), 1, 5),
+            NextStep::DeadLetter("refused".to_string())
+        );
+    }
+}
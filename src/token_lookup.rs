@@ -0,0 +1,44 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+pub type HmacSha256 = Hmac<Sha256>;
+
+// Tokens name the KV record they belong to (the JWT `sub`, the access token id). That name is
+// attacker controlled until the signature has been checked, which needs the record itself.
+// A lookup tag is an HMAC of the name under the server-wide TOKEN_LOOKUP_SECRET: without it a
+// forged token can never point us at a KV key, so it is rejected before any storage access.
+
+// Domain separation so a JWT tag can never be replayed as an access token tag and vice versa
+pub const JWT_LOOKUP_CONTEXT: &str = "jwt-sub";
+pub const ACCESS_TOKEN_LOOKUP_CONTEXT: &str = "pat-id";
+
+// 128 bits of the HMAC output are plenty to make tags unguessable
+const LOOKUP_TAG_BYTES: usize = 16;
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// HMAC accepts keys of any length, new_from_slice cannot fail
+pub fn hmac_sha256(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length")
+}
+
+fn lookup_mac(secret: &[u8], context: &str, key: &str) -> HmacSha256 {
+    let mut mac = hmac_sha256(secret);
+    mac.update(context.as_bytes());
+    mac.update(b":");
+    mac.update(key.as_bytes());
+    mac
+}
+
+// Compute the tag for a lookup key (hex, so it never clashes with token separators)
+pub fn lookup_tag(secret: &[u8], context: &str, key: &str) -> String {
+    to_hex(&lookup_mac(secret, context, key).finalize().into_bytes()[..LOOKUP_TAG_BYTES])
+}
+
+// Check a presented tag in constant time
+pub fn verify_lookup_tag(secret: &[u8], context: &str, key: &str, tag: &str) -> bool {
+    lookup_tag(secret, context, key).as_bytes().ct_eq(tag.as_bytes()).into()
+}
@@ -1,34 +1,64 @@
-use serde::Deserialize;
-use worker::*;
-
-#[derive(Deserialize)]
-struct TurnstileVerifyResponse {
-    success: bool,
-    #[serde(rename = "error-codes")]
-    error_codes: Option<Vec<String>>,
+use serde::Serialize;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::human_verifier::{HumanVerifier, VerificationError, VerifiedChallenge, VerifyOptions, post_siteverify, check_siteverify_response};
+
+const SITEVERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+// siteverify is retried once on network errors, the idempotency key makes the retry safe
+// (a token can only be redeemed once, the key lets the retry see the first outcome)
+const SITEVERIFY_ATTEMPTS: usize = 2;
+
+#[derive(Serialize)]
+struct TurnstileVerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<&'a str>,
+    idempotency_key: &'a str,
 }
 
-pub async fn verify_turnstile_token(token: &str, secret: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let mut init = RequestInit::new();
-    init.method = Method::Post;
-    
-    // Create form data for Turnstile verification
-    let form_data = format!("secret={}&response={}", secret, token);
-    init.body = Some(form_data.into());
-    
-    // Set content type header for form data
-    let mut headers = Headers::new();
-    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
-    init.headers = headers;
-
-    let request = Request::new_with_init("https://challenges.cloudflare.com/turnstile/v0/siteverify", &init)?;
-    
-    let mut response = Fetch::Request(request).send().await?;
-    let verify_response: TurnstileVerifyResponse = response.json().await?;
-
-    if verify_response.success {
-        Ok(())
-    } else {
-        Err("Turnstile verification failed".into())
+pub struct TurnstileVerifier {
+    secret: String,
+    verify_url: String,
+}
+
+impl TurnstileVerifier {
+    pub fn new(secret: String, verify_url: Option<String>) -> Self {
+        TurnstileVerifier {
+            secret,
+            verify_url: verify_url.unwrap_or_else(|| SITEVERIFY_URL.to_string()),
+        }
+    }
+}
+
+fn generate_idempotency_key() -> String {
+    // Random UUID v4, the format siteverify expects
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+impl HumanVerifier for TurnstileVerifier {
+    async fn verify(&self, token: &str, options: &VerifyOptions) -> std::result::Result<VerifiedChallenge, VerificationError> {
+        let idempotency_key = generate_idempotency_key();
+        let body = serde_urlencoded::to_string(TurnstileVerifyRequest {
+            secret: &self.secret,
+            response: token,
+            remoteip: options.remote_ip.as_deref(),
+            idempotency_key: &idempotency_key,
+        })
+        .map_err(|err| VerificationError::Unavailable(err.to_string()))?;
+
+        let mut last_error = String::new();
+        for _ in 0..SITEVERIFY_ATTEMPTS {
+            match post_siteverify(&self.verify_url, &body).await {
+                Ok(response) => return check_siteverify_response(&response, options, true),
+                Err(err) => last_error = err.to_string(),
+            }
+        }
+        Err(VerificationError::Unavailable(last_error))
     }
 }
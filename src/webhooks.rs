@@ -0,0 +1,209 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use worker::*;
+use chrono::Utc;
+use hmac::Mac;
+use base64::{Engine as _, engine::general_purpose};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use crate::token_lookup::{to_hex, hmac_sha256};
+use crate::logging;
+use crate::config::{env_list, env_parse};
+
+// Account lifecycle webhooks. Every URL in WEBHOOK_URLS receives a JSON POST for each event,
+// signed the Standard Webhooks way (https://www.standardwebhooks.com):
+//
+//     webhook-id         unique event id, the same on every retry so receivers can deduplicate
+//     webhook-timestamp  unix seconds of this attempt
+//     webhook-signature  "v1,<base64 HMAC-SHA256 of "<id>.<timestamp>.<body>" under WEBHOOK_SECRET>"
+//
+// A WEBHOOK_SECRET of the form "whsec_<base64>", as Standard Webhooks libraries generate them,
+// signs with the decoded bytes. Any other value is used as is.
+//
+// Delivery runs on the task queue when one is configured (see tasks.rs), otherwise after the
+// response has been sent (ctx.wait_until). Network errors, 429 and 5xx responses are retried with
+// exponential backoff, other statuses are final.
+
+pub const USER_CREATED: &str = "user.created";
+pub const USER_USERNAME_CHANGED: &str = "user.username_changed";
+pub const USER_PASSWORD_CHANGED: &str = "user.password_changed";
+pub const USER_DELETION_SCHEDULED: &str = "user.deletion_scheduled";
+pub const USER_RESTORED: &str = "user.restored";
+pub const USER_DELETED: &str = "user.deleted";
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF_MILLIS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub timestamp: i64,
+    pub data: serde_json::Value,
+}
+
+impl WebhookEvent {
+    pub fn new(event_type: &str, data: serde_json::Value) -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        WebhookEvent {
+            id: format!("evt_{}", to_hex(&id)),
+            event_type: event_type.to_string(),
+            timestamp: Utc::now().timestamp(),
+            data,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    secret: Vec<u8>,
+    events: Vec<String>, // Empty means every event
+    max_attempts: u32,
+}
+
+impl WebhookConfig {
+    // None when no webhook URLs are configured
+    pub fn from_env(env: &Env) -> std::result::Result<Option<Self>, Box<dyn std::error::Error>> {
+        let urls = env_list(env, "WEBHOOK_URLS");
+        if urls.is_empty() {
+            return Ok(None);
+        }
+
+        let secret = env
+            .secret("WEBHOOK_SECRET")
+            .map_err(|_| "WEBHOOK_SECRET is not set")?
+            .to_string();
+
+        Ok(Some(WebhookConfig {
+            urls,
+            secret: secret_key(&secret).ok_or("WEBHOOK_SECRET is not valid base64 after whsec_")?,
+            events: env_list(env, "WEBHOOK_EVENTS"),
+            max_attempts: env_parse(env, "WEBHOOK_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS).max(1),
+        }))
+    }
+
+    pub fn wants(&self, event_type: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|event| event == event_type)
+    }
+}
+
+fn secret_key(secret: &str) -> Option<Vec<u8>> {
+    match secret.strip_prefix("whsec_") {
+        Some(encoded) => general_purpose::STANDARD.decode(encoded).ok(),
+        None => Some(secret.as_bytes().to_vec()),
+    }
+}
+
+pub fn sign_payload(secret: &[u8], id: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = hmac_sha256(secret);
+    mac.update(format!("{}.{}.{}", id, timestamp, body).as_bytes());
+    format!("v1,{}", general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+// One POST, Ok(true) on 2xx, Ok(false) for a final refusal, Err when worth retrying
+pub async fn send_once(config: &WebhookConfig, url: &str, event: &WebhookEvent, body: &str) -> std::result::Result<bool, String> {
+    let timestamp = Utc::now().timestamp();
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json").map_err(|err| err.to_string())?;
+    headers.set("webhook-id", &event.id).map_err(|err| err.to_string())?;
+    headers.set("webhook-timestamp", &timestamp.to_string()).map_err(|err| err.to_string())?;
+    headers
+        .set("webhook-signature", &sign_payload(&config.secret, &event.id, timestamp, body))
+        .map_err(|err| err.to_string())?;
+
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.headers = headers;
+    init.body = Some(body.into());
+
+    let request = Request::new_with_init(url, &init).map_err(|err| err.to_string())?;
+    let response = Fetch::Request(request).send().await.map_err(|err| err.to_string())?;
+
+    match response.status_code() {
+        200..=299 => Ok(true),
+        429 | 500..=599 => Err(format!("status {}", response.status_code())),
+        _ => Ok(false),
+    }
+}
+
+// Deliver an event to one URL in this invocation, retrying with exponential backoff (1s, 2s, 4s, ...)
+pub async fn deliver(config: WebhookConfig, url: String, event: WebhookEvent) {
+    let body = match serde_json::to_string(&event) {
+        Ok(body) => body,
+        Err(err) => {
+            logging::error("webhook could not be encoded", serde_json::json!({
+                "event_id": event.id,
+                "error": err.to_string()
+            }));
+            return;
+        }
+    };
+
+    let mut backoff = INITIAL_BACKOFF_MILLIS;
+    for attempt in 1..=config.max_attempts {
+        match send_once(&config, &url, &event, &body).await {
+            Ok(true) => return,
+            Ok(false) => {
+                logging::error("webhook refused", serde_json::json!({
+                    "event_id": event.id,
+                    "event_type": event.event_type,
+                    "url": url
+                }));
+                return;
+            }
+            Err(err) if attempt < config.max_attempts => {
+                logging::warn("webhook delivery failed, retrying", serde_json::json!({
+                    "event_id": event.id,
+                    "url": url,
+                    "attempt": attempt,
+                    "error": err
+                }));
+                Delay::from(Duration::from_millis(backoff)).await;
+                backoff *= 2;
+            }
+            Err(err) => {
+                logging::error("webhook delivery failed", serde_json::json!({
+                    "event_id": event.id,
+                    "url": url,
+                    "attempt": attempt,
+                    "error": err
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vector of the Standard Webhooks reference libraries
+    const SECRET: &str = "whsec_MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw";
+    const MSG_ID: &str = "msg_p5jXN8AQM9LWM0D4loKWxJek";
+    const TIMESTAMP: i64 = 1614265330;
+    const PAYLOAD: &str = r#"{"test": 2432232314}"#;
+    const SIGNATURE: &str = "v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=";
+
+    #[test]
+    fn signature_matches_the_standard_webhooks_vector() {
+        let key = secret_key(SECRET).unwrap();
+        assert_eq!(sign_payload(&key, MSG_ID, TIMESTAMP, PAYLOAD), SIGNATURE);
+    }
+
+    #[test]
+    fn signature_covers_id_timestamp_and_payload() {
+        let key = secret_key(SECRET).unwrap();
+        assert_ne!(sign_payload(&key, "msg_other", TIMESTAMP, PAYLOAD), SIGNATURE);
+        assert_ne!(sign_payload(&key, MSG_ID, TIMESTAMP + 1, PAYLOAD), SIGNATURE);
+        assert_ne!(sign_payload(&key, MSG_ID, TIMESTAMP, r#"{"test": 2432232315}"#), SIGNATURE);
+    }
+
+    #[test]
+    fn plain_secrets_are_used_as_is() {
+        assert_eq!(secret_key("correct horse").unwrap(), b"correct horse");
+        assert_eq!(secret_key("whsec_not base64!"), None);
+    }
+}
@@ -1,6 +1,8 @@
 # Test script for the API in PowerShell
 # Make sure to replace the URL with your actual worker URL
 # and get a valid Turnstile token from your frontend
+# (against a local worker with CAPTCHA_PROVIDER=stub and ALLOW_CAPTCHA_STUB=true, use the
+# CAPTCHA_STUB_TOKEN value followed by a fresh ":<nonce>" per request, tokens are single use)
 
 $API_URL = "https://your-worker.your-subdomain.workers.dev"
 $TURNSTILE_TOKEN = "your_turnstile_token_here"
@@ -40,6 +42,7 @@ try {
     $body = @{
         user = "testuser"
         password = "testpassword123"
+        scope = "profile:read profile:write account:delete"
     } | ConvertTo-Json
 
     $response = Invoke-RestMethod -Uri "$API_URL/login" -Method Post -Headers $headers -Body $body
@@ -109,6 +112,14 @@ if ($JWT_TOKEN) {
 
         $response = Invoke-RestMethod -Uri "$API_URL/user" -Method Delete -Headers $headers
         $response | ConvertTo-Json
+
+        # Deletion has a grace period, undo it with the restore token
+        if ($response.restore_token) {
+            Write-Host "`nTesting Restore User Endpoint..."
+            $restoreBody = @{ token = $response.restore_token } | ConvertTo-Json
+            $restore = Invoke-RestMethod -Uri "$API_URL/user/restore" -Method Post -Body $restoreBody -ContentType "application/json"
+            $restore | ConvertTo-Json
+        }
     } catch {
         Write-Host "Error: $($_.Exception.Message)"
     }
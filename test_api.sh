@@ -3,6 +3,8 @@
 # Test script for the API
 # Make sure to replace the URL with your actual worker URL
 # and get a valid Turnstile token from your frontend
+# (against a local worker with CAPTCHA_PROVIDER=stub and ALLOW_CAPTCHA_STUB=true, use the
+# CAPTCHA_STUB_TOKEN value followed by a fresh ":<nonce>" per request, tokens are single use)
 
 API_URL="https://your-worker.your-subdomain.workers.dev"
 TURNSTILE_TOKEN="your_turnstile_token_here"
@@ -28,7 +30,8 @@ LOGIN_RESPONSE=$(curl -s -X POST "$API_URL/login" \
   -H "cf-turnstile-response: $TURNSTILE_TOKEN" \
   -d '{
     "user": "testuser",
-    "password": "testpassword123"
+    "password": "testpassword123",
+    "scope": "profile:read profile:write account:delete"
   }')
 
 echo "$LOGIN_RESPONSE"
@@ -77,9 +80,20 @@ fi
 echo "Testing Delete User Endpoint..."
 if [ -n "$JWT_TOKEN" ]; then
   echo "Using JWT token: $JWT_TOKEN"
-  curl -X DELETE "$API_URL/user" \
-    -H "Authorization: Bearer $JWT_TOKEN"
+  DELETE_RESPONSE=$(curl -s -X DELETE "$API_URL/user" \
+    -H "Authorization: Bearer $JWT_TOKEN")
+  echo "$DELETE_RESPONSE"
   echo -e "\n"
+
+  # Deletion has a grace period, undo it with the restore token
+  RESTORE_TOKEN=$(echo "$DELETE_RESPONSE" | grep -o '"restore_token":"[^"]*"' | cut -d'"' -f4)
+  if [ -n "$RESTORE_TOKEN" ]; then
+    echo "Testing Restore User Endpoint..."
+    curl -X POST "$API_URL/user/restore" \
+      -H "Content-Type: application/json" \
+      -d "{\"token\": \"$RESTORE_TOKEN\"}"
+    echo -e "\n"
+  fi
 else
   echo "Skipping delete test - no JWT token available"
 fi